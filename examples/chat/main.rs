@@ -1,6 +1,92 @@
-use rust_websocket::WebSocketServer;
+use rust_websocket::{CloseFrame, ConnectionId, ConnectionRegistry, HttpUpgradeRequest, Message, WebSocket, WebSocketHandler, WebSocketServer};
+
+static DEFAULT_CHANNEL: &str = "general";
+
+// Clients chat in channels. Besides plain messages, which go to the current channel, they can send
+//   /join <channel>   to join a channel and make it the current one
+//   /leave <channel>  to leave a channel
+//   /channels         to list the channels and how many clients are in them
+//   /who              to list the clients in the current channel
+struct ChatHandler {
+    // Kept from on_open, so we can tell the others when this client leaves.
+    connection: Option<(ConnectionRegistry, ConnectionId)>,
+    channel: String,
+}
+
+impl Default for ChatHandler {
+    fn default() -> Self {
+        ChatHandler { connection: None, channel: DEFAULT_CHANNEL.to_owned() }
+    }
+}
+
+impl ChatHandler {
+    fn handle_command(&mut self, socket: &mut WebSocket<'_>, registry: &ConnectionRegistry, id: ConnectionId, command: &str) {
+        let mut words = command.split_whitespace();
+        let reply = match (words.next(), words.next()) {
+            (Some("/join"), Some(channel)) => {
+                registry.join(id, channel).unwrap();
+                registry.publish_except(channel, id, &Message::Text(format!("Client {} joined #{}", id, channel)));
+                self.channel = channel.to_owned();
+                format!("You are now chatting in #{}", channel)
+            }
+            (Some("/leave"), Some(channel)) => {
+                if registry.leave(id, channel) {
+                    registry.publish(channel, &Message::Text(format!("Client {} left #{}", id, channel)));
+                }
+                format!("You left #{}", channel)
+            }
+            (Some("/channels"), None) => {
+                let channels: Vec<String> = registry.rooms().iter().map(|(channel, size)| format!("#{} ({})", channel, size)).collect();
+                format!("Channels: {}", channels.join(", "))
+            }
+            (Some("/who"), None) => {
+                let members: Vec<String> = registry.members(&self.channel).iter().map(ConnectionId::to_string).collect();
+                format!("In #{}: {}", self.channel, members.join(", "))
+            }
+            _ => format!("Unknown command: {}", command),
+        };
+
+        socket.send_text(&reply).unwrap();
+    }
+}
+
+impl WebSocketHandler for ChatHandler {
+    fn on_open(&mut self, socket: &mut WebSocket<'_>, request: &HttpUpgradeRequest) {
+        let registry = socket.registry().unwrap().clone();
+        let id = socket.connection_id().unwrap();
+        println!("Client {} connected to {}", id, request.path);
+
+        registry.join(id, &self.channel).unwrap();
+        socket.send_text(&format!("Welcome to the chat! You are in #{}", self.channel)).unwrap();
+        registry.publish_except(&self.channel, id, &Message::Text(format!("Client {} joined #{}", id, self.channel)));
+        self.connection = Some((registry, id));
+    }
+
+    fn on_message(&mut self, socket: &mut WebSocket<'_>, message: Message) {
+        let (registry, id, text) = match (&self.connection, message) {
+            (Some((registry, id)), Message::Text(text)) => (registry.clone(), *id, text),
+            _ => return,
+        };
+
+        if text.starts_with('/') {
+            self.handle_command(socket, &registry, id, &text);
+        } else if registry.members(&self.channel).contains(&id) {
+            println!("Received from {} in #{}: {}", id, self.channel, text);
+            registry.publish(&self.channel, &Message::Text(format!("[#{}] {}: {}", self.channel, id, text)));
+        } else {
+            socket.send_text(&format!("You aren't in #{} anymore, /join a channel to chat", self.channel)).unwrap();
+        }
+    }
+
+    fn on_close(&mut self, close_frame: CloseFrame) {
+        if let Some((registry, id)) = &self.connection {
+            println!("Client {} disconnected: {:?}", id, close_frame);
+            registry.broadcast_except(*id, &Message::Text(format!("Client {} left", id)));
+        }
+    }
+}
 
 fn main() {
-    let server = WebSocketServer::new(3000, 4);
-    server.start();
+    let server = WebSocketServer::bind("127.0.0.1:3000", 4, ChatHandler::default).unwrap();
+    server.start().unwrap().wait();
 }
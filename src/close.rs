@@ -0,0 +1,200 @@
+use std::convert::TryInto;
+
+/// The status codes from RFC 6455 section 7.4.1, plus the ranges reserved
+/// for libraries (3000-3999) and applications (4000-4999).
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum CloseCode {
+    Normal,
+    GoingAway,
+    ProtocolError,
+    UnsupportedData,
+    // Reserved: the close frame didn't contain a status code.
+    NoStatus,
+    // Reserved: the connection was closed without a close frame.
+    Abnormal,
+    InvalidPayload,
+    PolicyViolation,
+    MessageTooBig,
+    MandatoryExtension,
+    InternalError,
+    ServiceRestart,
+    TryAgainLater,
+    BadGateway,
+    // Reserved: the TLS handshake failed.
+    TlsHandshake,
+    Other(u16),
+}
+
+impl CloseCode {
+    pub fn from_u16(value: u16) -> CloseCode {
+        match value {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::GoingAway,
+            1002 => CloseCode::ProtocolError,
+            1003 => CloseCode::UnsupportedData,
+            1005 => CloseCode::NoStatus,
+            1006 => CloseCode::Abnormal,
+            1007 => CloseCode::InvalidPayload,
+            1008 => CloseCode::PolicyViolation,
+            1009 => CloseCode::MessageTooBig,
+            1010 => CloseCode::MandatoryExtension,
+            1011 => CloseCode::InternalError,
+            1012 => CloseCode::ServiceRestart,
+            1013 => CloseCode::TryAgainLater,
+            1014 => CloseCode::BadGateway,
+            1015 => CloseCode::TlsHandshake,
+            _ => CloseCode::Other(value),
+        }
+    }
+
+    pub fn as_u16(self) -> u16 {
+        match self {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::UnsupportedData => 1003,
+            CloseCode::NoStatus => 1005,
+            CloseCode::Abnormal => 1006,
+            CloseCode::InvalidPayload => 1007,
+            CloseCode::PolicyViolation => 1008,
+            CloseCode::MessageTooBig => 1009,
+            CloseCode::MandatoryExtension => 1010,
+            CloseCode::InternalError => 1011,
+            CloseCode::ServiceRestart => 1012,
+            CloseCode::TryAgainLater => 1013,
+            CloseCode::BadGateway => 1014,
+            CloseCode::TlsHandshake => 1015,
+            CloseCode::Other(value) => value,
+        }
+    }
+
+    /// Whether the code may be sent in a close frame. 1005, 1006 and 1015 are reserved
+    /// for reporting locally, and codes outside the defined ranges are not allowed.
+    pub fn is_allowed_on_wire(self) -> bool {
+        matches!(self.as_u16(), 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct CloseFrame {
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+impl CloseFrame {
+    /// Parses the payload of a close frame. On failure, the error is the
+    /// code we should close the connection with.
+    pub(crate) fn from_payload(payload: &[u8]) -> Result<CloseFrame, CloseCode> {
+        // A close frame doesn't have to have a body, but if it does,
+        // it must start with a 2-byte status code.
+        if payload.is_empty() {
+            return Ok(CloseFrame {
+                code: CloseCode::NoStatus,
+                reason: String::new(),
+            });
+        }
+
+        if payload.len() < 2 {
+            return Err(CloseCode::ProtocolError);
+        }
+
+        let code = CloseCode::from_u16(u16::from_be_bytes(payload[..2].try_into().unwrap()));
+        if !code.is_allowed_on_wire() {
+            return Err(CloseCode::ProtocolError);
+        }
+
+        let reason =
+            String::from_utf8(payload[2..].to_vec()).map_err(|_| CloseCode::InvalidPayload)?;
+
+        Ok(CloseFrame { code, reason })
+    }
+
+    pub(crate) fn to_payload(&self) -> Vec<u8> {
+        // NoStatus means that the frame didn't have a body in the first place
+        if self.code == CloseCode::NoStatus {
+            return Vec::new();
+        }
+
+        let mut payload = self.code.as_u16().to_be_bytes().to_vec();
+        payload.extend_from_slice(self.reason.as_bytes());
+        payload
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_parses_code_and_reason() {
+        let payload = [vec![0x03, 0xE9], b"Going away".to_vec()].concat();
+
+        assert_eq!(
+            CloseFrame::from_payload(&payload),
+            Ok(CloseFrame {
+                code: CloseCode::GoingAway,
+                reason: "Going away".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn it_parses_empty_payload_as_no_status() {
+        assert_eq!(
+            CloseFrame::from_payload(&[]),
+            Ok(CloseFrame {
+                code: CloseCode::NoStatus,
+                reason: String::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn it_rejects_one_byte_payload() {
+        assert_eq!(
+            CloseFrame::from_payload(&[0x03]),
+            Err(CloseCode::ProtocolError)
+        );
+    }
+
+    #[test]
+    fn it_rejects_codes_not_allowed_on_the_wire() {
+        for code in &[0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000] {
+            assert_eq!(
+                CloseFrame::from_payload(&u16::to_be_bytes(*code)),
+                Err(CloseCode::ProtocolError),
+                "code {} should be rejected",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn it_accepts_library_and_application_codes() {
+        for code in &[3000, 3999, 4000, 4999] {
+            assert_eq!(
+                CloseFrame::from_payload(&u16::to_be_bytes(*code)).map(|frame| frame.code),
+                Ok(CloseCode::Other(*code))
+            );
+        }
+    }
+
+    #[test]
+    fn it_rejects_reason_with_invalid_utf8() {
+        assert_eq!(
+            CloseFrame::from_payload(&[0x03, 0xE8, 0xC3, 0x28]),
+            Err(CloseCode::InvalidPayload)
+        );
+    }
+
+    #[test]
+    fn it_roundtrips_through_payload() {
+        let frame = CloseFrame {
+            code: CloseCode::Normal,
+            reason: "bye".to_owned(),
+        };
+
+        assert_eq!(frame.to_payload(), vec![0x03, 0xE8, b'b', b'y', b'e']);
+        assert_eq!(CloseFrame::from_payload(&frame.to_payload()), Ok(frame));
+    }
+}
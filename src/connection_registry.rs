@@ -0,0 +1,173 @@
+use crate::error::Error;
+use crate::frame_encoder::FrameEncoder;
+use crate::message::Message;
+use crate::server_handle::ServerState;
+use crate::websocket::Command;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Identifies a connection for as long as the server runs. IDs are never reused.
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Clone, Copy)]
+pub struct ConnectionId(pub(crate) u64);
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// What the server knows about an open connection.
+#[derive(PartialEq, Debug, Clone)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    pub remote_addr: SocketAddr,
+
+    // The path the client asked for in the upgrade request, like "/chat?room=1".
+    pub path: String,
+
+    // The rooms the connection has joined, in alphabetical order.
+    pub rooms: Vec<String>,
+}
+
+/// A message encoded once and shared by every connection it's sent to.
+#[derive(PartialEq, Debug)]
+pub(crate) struct EncodedMessage {
+    pub message: Message,
+
+    // The frames as a server sends them, unmasked and unfragmented.
+    pub bytes: Vec<u8>,
+}
+
+impl EncodedMessage {
+    fn new(message: &Message) -> Arc<EncodedMessage> {
+        Arc::new(EncodedMessage {
+            message: message.clone(),
+            bytes: FrameEncoder::new().encode_message(message),
+        })
+    }
+}
+
+/// The open connections of a server, for sending messages to connections other than
+/// the one a handler is running on. Handlers get it with `WebSocket::registry`, and
+/// other code with `WebSocketServer::registry` or `ServerHandle::registry`.
+///
+/// Connections can also join named rooms, so messages can be published to everyone
+/// in a room. A connection leaves all its rooms when it closes.
+///
+/// Messages are queued on the connections, which pick them up between reads, so a message
+/// can wait up to 100 milliseconds before it's written. A connection with 1024 messages
+/// waiting has fallen too far behind, usually because its client stopped reading. It's cut
+/// off instead of queueing more, and doesn't count as a recipient.
+///
+/// Connections only show up once their opening handshake has completed.
+#[derive(Clone)]
+pub struct ConnectionRegistry {
+    state: Arc<ServerState>,
+}
+
+impl ConnectionRegistry {
+    pub(crate) fn new(state: Arc<ServerState>) -> ConnectionRegistry {
+        ConnectionRegistry { state }
+    }
+
+    /// Sends the message to every open connection. Returns how many connections it was queued on.
+    pub fn broadcast(&self, message: &Message) -> usize {
+        self.state
+            .send_to_all(&Command::Send(EncodedMessage::new(message)), None, None)
+    }
+
+    /// Sends the message to every open connection but `except`, usually the one it came from.
+    /// Returns how many connections it was queued on.
+    pub fn broadcast_except(&self, except: ConnectionId, message: &Message) -> usize {
+        self.state.send_to_all(
+            &Command::Send(EncodedMessage::new(message)),
+            None,
+            Some(except),
+        )
+    }
+
+    /// Sends the message to a single connection.
+    /// Returns `Error::ConnectionClosed` if there is no open connection with that ID.
+    pub fn send_to(&self, id: ConnectionId, message: &Message) -> Result<(), Error> {
+        if self
+            .state
+            .send_to(id, Command::Send(EncodedMessage::new(message)))
+        {
+            Ok(())
+        } else {
+            Err(Error::ConnectionClosed)
+        }
+    }
+
+    /// Adds the connection to the room, creating the room if it has no members yet.
+    /// Returns `Error::ConnectionClosed` if there is no open connection with that ID.
+    pub fn join(&self, id: ConnectionId, room: &str) -> Result<(), Error> {
+        if self.state.join(id, room) {
+            Ok(())
+        } else {
+            Err(Error::ConnectionClosed)
+        }
+    }
+
+    /// Removes the connection from the room. Returns false if it wasn't a member.
+    pub fn leave(&self, id: ConnectionId, room: &str) -> bool {
+        self.state.leave(id, room)
+    }
+
+    /// Sends the message to every member of the room. Returns how many connections it was queued on.
+    pub fn publish(&self, room: &str, message: &Message) -> usize {
+        self.state.send_to_all(
+            &Command::Send(EncodedMessage::new(message)),
+            Some(room),
+            None,
+        )
+    }
+
+    /// Sends the message to every member of the room but `except`.
+    /// Returns how many connections it was queued on.
+    pub fn publish_except(&self, room: &str, except: ConnectionId, message: &Message) -> usize {
+        self.state.send_to_all(
+            &Command::Send(EncodedMessage::new(message)),
+            Some(room),
+            Some(except),
+        )
+    }
+
+    /// The members of the room, ordered by ID. Empty if nobody has joined it.
+    pub fn members(&self, room: &str) -> Vec<ConnectionId> {
+        self.state.room_members(room)
+    }
+
+    pub fn room_size(&self, room: &str) -> usize {
+        self.members(room).len()
+    }
+
+    /// Every room with at least one member, and how many members it has.
+    pub fn rooms(&self) -> BTreeMap<String, usize> {
+        self.state.room_sizes()
+    }
+
+    pub fn connection(&self, id: ConnectionId) -> Option<ConnectionInfo> {
+        self.state.connection_info(id)
+    }
+
+    /// Every open connection, ordered by ID.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.state.connection_infos()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_encodes_messages_as_a_server_frame() {
+        let encoded = EncodedMessage::new(&Message::Text("Hi".to_owned()));
+
+        assert_eq!(encoded.bytes, vec![0b10000001, 2, b'H', b'i']);
+        assert_eq!(encoded.message, Message::Text("Hi".to_owned()));
+    }
+}
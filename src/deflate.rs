@@ -0,0 +1,444 @@
+use crate::close::CloseCode;
+use crate::error::Error;
+use crate::extension::{Extension, ExtensionParams};
+use crate::frame_parser::{DataFrame, Opcode};
+use crate::http::ExtensionOffer;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+static EXTENSION_NAME: &str = "permessage-deflate";
+
+// Compressed messages are marked with the first reserved bit.
+static RSV1: u8 = 0b01000000;
+
+// Every compressed message ends with an empty stored block, which is left out on the wire.
+static EMPTY_BLOCK_TAIL: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+// We always compress with the largest window, since that is the only one our deflate backend supports.
+static MAX_WINDOW_BITS: u8 = 15;
+
+static OUTPUT_CHUNK_SIZE: usize = 16 * 1024; // bytes
+
+/// Settings for the permessage-deflate extension from RFC 7692.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct DeflateConfig {
+    // Start every message we send with an empty compression context. Saves memory
+    // on the server at the cost of a worse compression ratio.
+    pub server_no_context_takeover: bool,
+
+    // Ask the client to do the same for the messages it sends.
+    pub client_no_context_takeover: bool,
+
+    // From 0 (no compression) to 9 (best compression).
+    pub compression_level: u32,
+}
+
+impl Default for DeflateConfig {
+    fn default() -> Self {
+        DeflateConfig {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            compression_level: 6,
+        }
+    }
+}
+
+/// The permessage-deflate extension. Outgoing messages are compressed and marked
+/// with RSV1, and incoming messages marked with RSV1 are decompressed.
+pub(crate) struct PerMessageDeflate {
+    config: DeflateConfig,
+    max_message_size: Option<usize>,
+
+    // Set once an offer has been accepted.
+    deflater: Option<Deflater>,
+    inflater: Option<Inflater>,
+}
+
+impl PerMessageDeflate {
+    /// Decompressed messages larger than `max_message_size` are rejected.
+    pub fn new(config: DeflateConfig, max_message_size: Option<usize>) -> PerMessageDeflate {
+        PerMessageDeflate {
+            config,
+            max_message_size,
+            deflater: None,
+            inflater: None,
+        }
+    }
+}
+
+impl Extension for PerMessageDeflate {
+    fn name(&self) -> &str {
+        EXTENSION_NAME
+    }
+
+    fn negotiate(&mut self, offer: &ExtensionOffer) -> Option<ExtensionParams> {
+        let (server_no_context_takeover, client_no_context_takeover) =
+            accept_offer(&self.config, offer)?;
+
+        self.deflater = Some(Deflater::new(
+            self.config.compression_level,
+            server_no_context_takeover,
+        ));
+        self.inflater = Some(Inflater::new(client_no_context_takeover));
+
+        let mut params = Vec::new();
+        if server_no_context_takeover {
+            params.push(("server_no_context_takeover".to_owned(), None));
+        }
+        if client_no_context_takeover {
+            params.push(("client_no_context_takeover".to_owned(), None));
+        }
+        Some(params)
+    }
+
+    fn reserved_bits(&self) -> u8 {
+        RSV1
+    }
+
+    fn decode_frame(&mut self, frame: DataFrame) -> Result<DataFrame, Error> {
+        // Only the first frame of a message says whether the message is compressed
+        if frame.rsv1 && !matches!(frame.opcode, Opcode::Text | Opcode::Binary) {
+            return Err(Error::Protocol(CloseCode::ProtocolError));
+        }
+
+        Ok(frame)
+    }
+
+    fn decode_message(&mut self, mut message: DataFrame) -> Result<DataFrame, Error> {
+        let inflater = match self.inflater.as_mut() {
+            Some(inflater) if message.rsv1 => inflater,
+            _ => return Ok(message),
+        };
+
+        let payload = message.payload_bytes.take().unwrap_or_default();
+        let payload =
+            inflater
+                .inflate(&payload, self.max_message_size)
+                .map_err(|error| match error {
+                    InflateError::TooLarge => {
+                        Error::Capacity("decompressed message exceeds the maximum message size")
+                    }
+                    InflateError::InvalidData => Error::Protocol(CloseCode::InvalidPayload),
+                })?;
+
+        message.rsv1 = false;
+        message.payload_bytes = Some(payload);
+        Ok(message)
+    }
+
+    fn encode_message(&mut self, mut message: DataFrame) -> Result<DataFrame, Error> {
+        if let Some(deflater) = self.deflater.as_mut() {
+            let payload = message.payload_bytes.take().unwrap_or_default();
+            message.payload_bytes = Some(deflater.deflate(&payload));
+            message.rsv1 = true;
+        }
+
+        Ok(message)
+    }
+}
+
+/// Returns whether the server and the client should reset their compression
+/// context after every message, or `None` if we can't accept the offer.
+fn accept_offer(config: &DeflateConfig, offer: &ExtensionOffer) -> Option<(bool, bool)> {
+    let mut server_no_context_takeover = config.server_no_context_takeover;
+    let mut seen: Vec<&str> = Vec::new();
+
+    for (name, value) in &offer.params {
+        // Each parameter may only appear once in an offer
+        if seen.contains(&name.as_str()) {
+            return None;
+        }
+        seen.push(name);
+
+        match (name.as_str(), value) {
+            ("server_no_context_takeover", None) => server_no_context_takeover = true,
+            // The client tells us it could reset its context, but it's up to us to ask for it
+            ("client_no_context_takeover", None) => {}
+            // We can only compress with the largest window, so we decline offers that limit it
+            ("server_max_window_bits", Some(bits)) => match parse_window_bits(bits) {
+                Some(bits) if bits == MAX_WINDOW_BITS => {}
+                _ => return None,
+            },
+            // The client supports a smaller window, which we don't need. Inflating with
+            // the largest window works no matter which window the client compresses with.
+            ("client_max_window_bits", None) => {}
+            ("client_max_window_bits", Some(bits)) => {
+                parse_window_bits(bits)?;
+            }
+            _ => return None,
+        }
+    }
+
+    Some((
+        server_no_context_takeover,
+        config.client_no_context_takeover,
+    ))
+}
+
+fn parse_window_bits(bits: &str) -> Option<u8> {
+    match bits.parse() {
+        Ok(bits) if (8..=15).contains(&bits) => Some(bits),
+        _ => None,
+    }
+}
+
+/// Compresses outgoing messages.
+struct Deflater {
+    compress: Compress,
+    no_context_takeover: bool,
+}
+
+impl Deflater {
+    pub fn new(compression_level: u32, no_context_takeover: bool) -> Deflater {
+        Deflater {
+            compress: Compress::new(Compression::new(compression_level), false),
+            no_context_takeover,
+        }
+    }
+
+    pub fn deflate(&mut self, payload: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(payload.len() / 2 + EMPTY_BLOCK_TAIL.len());
+        let start_in = self.compress.total_in();
+
+        loop {
+            let consumed = (self.compress.total_in() - start_in) as usize;
+            output.reserve(OUTPUT_CHUNK_SIZE);
+            self.compress
+                .compress_vec(&payload[consumed..], &mut output, FlushCompress::Sync)
+                .expect("compressing into a vector can't fail");
+
+            // A sync flush is finished once it has room to spare in the output
+            let consumed = (self.compress.total_in() - start_in) as usize;
+            if consumed == payload.len() && output.len() < output.capacity() {
+                break;
+            }
+        }
+
+        if output.ends_with(&EMPTY_BLOCK_TAIL) {
+            output.truncate(output.len() - EMPTY_BLOCK_TAIL.len());
+        }
+
+        if self.no_context_takeover {
+            self.compress.reset();
+        }
+
+        output
+    }
+}
+
+/// Decompresses incoming messages.
+struct Inflater {
+    decompress: Decompress,
+    no_context_takeover: bool,
+}
+
+#[derive(PartialEq, Debug)]
+enum InflateError {
+    InvalidData,
+    TooLarge,
+}
+
+impl Inflater {
+    pub fn new(no_context_takeover: bool) -> Inflater {
+        Inflater {
+            decompress: Decompress::new(false),
+            no_context_takeover,
+        }
+    }
+
+    /// Fails with `TooLarge` as soon as the output grows beyond `max_size`, so a small
+    /// compressed message can't make us allocate huge amounts of memory.
+    pub fn inflate(
+        &mut self,
+        payload: &[u8],
+        max_size: Option<usize>,
+    ) -> Result<Vec<u8>, InflateError> {
+        let input = [payload, &EMPTY_BLOCK_TAIL[..]].concat();
+        let mut output = Vec::with_capacity(payload.len() * 2);
+        let start_in = self.decompress.total_in();
+
+        loop {
+            let consumed = (self.decompress.total_in() - start_in) as usize;
+            let produced = output.len();
+            output.reserve(OUTPUT_CHUNK_SIZE);
+            let status = self
+                .decompress
+                .decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)
+                .map_err(|_| InflateError::InvalidData)?;
+
+            if max_size.is_some_and(|max_size| output.len() > max_size) {
+                return Err(InflateError::TooLarge);
+            }
+
+            // The client ended the deflate stream, so the next message starts a new one
+            if status == Status::StreamEnd {
+                self.decompress.reset(false);
+                return Ok(output);
+            }
+
+            let all_consumed = (self.decompress.total_in() - start_in) as usize == input.len();
+            if all_consumed && output.len() < output.capacity() {
+                break;
+            }
+
+            // Without progress, the data can't be decompressed any further
+            let made_progress = self.decompress.total_in() - start_in != consumed as u64
+                || output.len() != produced;
+            if !made_progress {
+                return Err(InflateError::InvalidData);
+            }
+        }
+
+        if self.no_context_takeover {
+            self.decompress.reset(false);
+        }
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn offer(params: &[(&str, Option<&str>)]) -> ExtensionOffer {
+        ExtensionOffer {
+            name: EXTENSION_NAME.to_owned(),
+            params: params
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.map(|value| value.to_owned())))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn it_inflates_the_example_from_the_rfc() {
+        let mut inflater = Inflater::new(false);
+
+        let payload = [0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
+        assert_eq!(inflater.inflate(&payload, None), Ok(b"Hello".to_vec()));
+
+        // The second message reuses the context of the first one
+        let payload = [0xf2, 0x00, 0x11, 0x00, 0x00];
+        assert_eq!(inflater.inflate(&payload, None), Ok(b"Hello".to_vec()));
+    }
+
+    #[test]
+    fn it_roundtrips_with_and_without_context_takeover() {
+        for no_context_takeover in &[false, true] {
+            let mut deflater = Deflater::new(6, *no_context_takeover);
+            let mut inflater = Inflater::new(*no_context_takeover);
+            let json =
+                br#"{"id": 1, "method": "subscribe", "params": ["ticker", "ticker"]}"#.repeat(100);
+
+            for _ in 0..3 {
+                let compressed = deflater.deflate(&json);
+                assert!(compressed.len() < json.len() / 10);
+                assert!(!compressed.ends_with(&EMPTY_BLOCK_TAIL));
+                assert_eq!(inflater.inflate(&compressed, None), Ok(json.clone()));
+            }
+        }
+    }
+
+    #[test]
+    fn it_refuses_to_inflate_beyond_the_maximum_size() {
+        let compressed = Deflater::new(9, false).deflate(&[0; 1_000_000]);
+
+        assert_eq!(
+            Inflater::new(false).inflate(&compressed, Some(1000)),
+            Err(InflateError::TooLarge)
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_data() {
+        assert_eq!(
+            Inflater::new(false).inflate(&[0xff, 0xff, 0xff], None),
+            Err(InflateError::InvalidData)
+        );
+    }
+
+    fn negotiate(config: DeflateConfig, offer: &ExtensionOffer) -> Option<ExtensionParams> {
+        PerMessageDeflate::new(config, None).negotiate(offer)
+    }
+
+    #[test]
+    fn it_negotiates_parameters() {
+        let config = DeflateConfig {
+            client_no_context_takeover: true,
+            ..DeflateConfig::default()
+        };
+        let offer = offer(&[
+            ("server_no_context_takeover", None),
+            ("client_max_window_bits", None),
+        ]);
+
+        assert_eq!(
+            negotiate(config, &offer),
+            Some(vec![
+                ("server_no_context_takeover".to_owned(), None),
+                ("client_no_context_takeover".to_owned(), None),
+            ])
+        );
+    }
+
+    #[test]
+    fn it_accepts_the_largest_server_window() {
+        let offer = offer(&[("server_max_window_bits", Some("15"))]);
+
+        assert_eq!(
+            negotiate(DeflateConfig::default(), &offer),
+            Some(Vec::new())
+        );
+    }
+
+    #[test]
+    fn it_declines_invalid_offers() {
+        let invalid_offers = [
+            offer(&[("unknown", None)]),
+            offer(&[("server_no_context_takeover", Some("1"))]),
+            offer(&[("server_max_window_bits", Some("10"))]),
+            offer(&[("client_max_window_bits", Some("16"))]),
+            offer(&[("server_max_window_bits", None)]),
+            offer(&[
+                ("server_no_context_takeover", None),
+                ("server_no_context_takeover", None),
+            ]),
+        ];
+
+        for invalid_offer in &invalid_offers {
+            assert_eq!(
+                negotiate(DeflateConfig::default(), invalid_offer),
+                None,
+                "{:?} should be declined",
+                invalid_offer
+            );
+        }
+    }
+
+    #[test]
+    fn it_compresses_messages_and_rejects_compression_bit_on_other_frames() {
+        let mut deflate = PerMessageDeflate::new(DeflateConfig::default(), None);
+        deflate.negotiate(&offer(&[])).unwrap();
+
+        // The example from RFC 7692 section 7.2.3.1
+        let message = deflate
+            .encode_message(DataFrame::new(true, Opcode::Text, b"Hello".to_vec()))
+            .unwrap();
+        assert!(message.rsv1);
+        assert_eq!(
+            message.payload_bytes,
+            Some(vec![0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00])
+        );
+
+        let mut ping = DataFrame::new(true, Opcode::Ping, Vec::new());
+        ping.rsv1 = true;
+        assert!(matches!(
+            deflate.decode_frame(ping),
+            Err(Error::Protocol(CloseCode::ProtocolError))
+        ));
+
+        let mut continuation = DataFrame::new(true, Opcode::Continuation, Vec::new());
+        continuation.rsv1 = true;
+        assert!(deflate.decode_frame(continuation).is_err());
+    }
+}
@@ -0,0 +1,115 @@
+use crate::{close::CloseCode, frame_parser::ParseError, message::AssemblerError};
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    // Reading from or writing to the stream failed.
+    Io(std::io::Error),
+
+    // The opening handshake failed, for example because the upgrade request was malformed.
+    Handshake(String),
+
+    // The peer broke the protocol. The close code is the one we close the connection with.
+    Protocol(CloseCode),
+
+    // A text message or the handshake request wasn't valid UTF-8.
+    Utf8(std::str::Utf8Error),
+
+    // A message, frame or request was larger than we allow.
+    Capacity(&'static str),
+
+    // The close code is reserved and may not be sent in a close frame, like 1005 or 1006.
+    InvalidCloseCode(CloseCode),
+
+    // The connection isn't open, either because the handshake hasn't happened yet
+    // or because the connection has been closed.
+    ConnectionClosed,
+
+    // Setting up TLS failed, for example because the certificate or key was invalid.
+    #[cfg(feature = "rustls")]
+    Tls(rustls::Error),
+}
+
+impl Error {
+    /// The code to close the connection with when this error happens on an open connection.
+    pub fn close_code(&self) -> CloseCode {
+        match self {
+            Error::Protocol(code) => *code,
+            Error::Utf8(_) => CloseCode::InvalidPayload,
+            Error::Capacity(_) => CloseCode::MessageTooBig,
+            _ => CloseCode::InternalError,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "I/O error: {}", error),
+            Error::Handshake(reason) => write!(f, "handshake failed: {}", reason),
+            Error::Protocol(code) => write!(f, "protocol error, closing with {}", code.as_u16()),
+            Error::Utf8(error) => write!(f, "invalid UTF-8: {}", error),
+            Error::Capacity(reason) => write!(f, "capacity exceeded: {}", reason),
+            Error::InvalidCloseCode(code) => {
+                write!(f, "close code {} may not be sent", code.as_u16())
+            }
+            Error::ConnectionClosed => write!(f, "the connection is closed"),
+            #[cfg(feature = "rustls")]
+            Error::Tls(error) => write!(f, "TLS error: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            Error::Utf8(error) => Some(error),
+            #[cfg(feature = "rustls")]
+            Error::Tls(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<std::str::Utf8Error> for Error {
+    fn from(error: std::str::Utf8Error) -> Self {
+        Error::Utf8(error)
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl From<rustls::Error> for Error {
+    fn from(error: rustls::Error) -> Self {
+        Error::Tls(error)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(error: ParseError) -> Self {
+        match error {
+            ParseError::PayloadTooLarge => {
+                Error::Capacity("frame payload exceeds the maximum message size")
+            }
+            _ => Error::Protocol(CloseCode::ProtocolError),
+        }
+    }
+}
+
+impl From<AssemblerError> for Error {
+    fn from(error: AssemblerError) -> Self {
+        match error {
+            AssemblerError::InvalidUtf8(error) => Error::Utf8(error),
+            AssemblerError::MessageTooBig => {
+                Error::Capacity("message exceeds the maximum message size")
+            }
+            _ => Error::Protocol(CloseCode::ProtocolError),
+        }
+    }
+}
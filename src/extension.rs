@@ -0,0 +1,246 @@
+use crate::error::Error;
+use crate::frame_parser::DataFrame;
+use crate::http::ExtensionOffer;
+
+/// The parameters of an accepted extension, in the order they are sent back to the client.
+pub type ExtensionParams = Vec<(String, Option<String>)>;
+
+/// An extension that transforms frames and messages on a connection, like permessage-deflate.
+///
+/// Every connection gets its own instance, which takes part in the negotiation of the
+/// `Sec-WebSocket-Extensions` header. If it's accepted, it sees every frame and message sent
+/// and received on the connection. Extensions are applied in the order the client offered them
+/// when sending, and in the reverse order when receiving.
+pub trait Extension: Send {
+    /// The name of the extension in `Sec-WebSocket-Extensions`, like "permessage-deflate".
+    fn name(&self) -> &str;
+
+    /// Called with each offer the client made for this extension, until one is accepted.
+    /// Returns the parameters to answer with, or `None` to decline the offer.
+    fn negotiate(&mut self, offer: &ExtensionOffer) -> Option<ExtensionParams>;
+
+    /// The RSV bits the extension gives a meaning, in their position in the first
+    /// byte of a frame. RSV1 is `0b01000000`, RSV2 `0b00100000` and RSV3 `0b00010000`.
+    /// Two extensions claiming the same bit can't be used together.
+    fn reserved_bits(&self) -> u8 {
+        0
+    }
+
+    /// Transforms a frame right after it has been parsed, before fragments are joined.
+    fn decode_frame(&mut self, frame: DataFrame) -> Result<DataFrame, Error> {
+        Ok(frame)
+    }
+
+    /// Transforms a whole text or binary message, given as a single frame with
+    /// the RSV bits of its first fragment, before it's passed to the handler.
+    fn decode_message(&mut self, message: DataFrame) -> Result<DataFrame, Error> {
+        Ok(message)
+    }
+
+    /// Transforms a whole text or binary message before it's fragmented and sent.
+    fn encode_message(&mut self, message: DataFrame) -> Result<DataFrame, Error> {
+        Ok(message)
+    }
+
+    /// Transforms a frame right before it's sent.
+    fn encode_frame(&mut self, frame: DataFrame) -> Result<DataFrame, Error> {
+        Ok(frame)
+    }
+}
+
+/// The extensions negotiated for a connection, in the order they were accepted.
+#[derive(Default)]
+pub(crate) struct ExtensionChain {
+    extensions: Vec<Box<dyn Extension>>,
+}
+
+impl ExtensionChain {
+    /// Goes through the offers in the client's order and accepts each one that one of our
+    /// extensions agrees to. Returns the chain along with the values for the response header.
+    pub fn negotiate(
+        mut candidates: Vec<Box<dyn Extension>>,
+        offers: &[ExtensionOffer],
+    ) -> (ExtensionChain, Vec<String>) {
+        let mut chain = ExtensionChain::default();
+        let mut header_values = Vec::new();
+
+        for offer in offers {
+            let claimed_bits = chain.reserved_bits();
+
+            let accepted = candidates
+                .iter_mut()
+                .enumerate()
+                .find_map(|(index, extension)| {
+                    if extension.name() != offer.name
+                        || extension.reserved_bits() & claimed_bits != 0
+                    {
+                        return None;
+                    }
+
+                    extension.negotiate(offer).map(|params| (index, params))
+                });
+
+            if let Some((index, params)) = accepted {
+                let extension = candidates.remove(index);
+                header_values.push(to_header_value(extension.name(), &params));
+                chain.extensions.push(extension);
+            }
+        }
+
+        (chain, header_values)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.extensions.is_empty()
+    }
+
+    pub fn reserved_bits(&self) -> u8 {
+        self.extensions
+            .iter()
+            .fold(0, |bits, extension| bits | extension.reserved_bits())
+    }
+
+    pub fn decode_frame(&mut self, frame: DataFrame) -> Result<DataFrame, Error> {
+        self.extensions
+            .iter_mut()
+            .rev()
+            .try_fold(frame, |frame, extension| extension.decode_frame(frame))
+    }
+
+    pub fn decode_message(&mut self, message: DataFrame) -> Result<DataFrame, Error> {
+        self.extensions
+            .iter_mut()
+            .rev()
+            .try_fold(message, |message, extension| {
+                extension.decode_message(message)
+            })
+    }
+
+    pub fn encode_message(&mut self, message: DataFrame) -> Result<DataFrame, Error> {
+        self.extensions
+            .iter_mut()
+            .try_fold(message, |message, extension| {
+                extension.encode_message(message)
+            })
+    }
+
+    pub fn encode_frame(&mut self, frame: DataFrame) -> Result<DataFrame, Error> {
+        self.extensions
+            .iter_mut()
+            .try_fold(frame, |frame, extension| extension.encode_frame(frame))
+    }
+}
+
+fn to_header_value(name: &str, params: &[(String, Option<String>)]) -> String {
+    let mut value = name.to_owned();
+    for (param_name, param_value) in params {
+        value.push_str("; ");
+        value.push_str(param_name);
+        if let Some(param_value) = param_value {
+            value.push('=');
+            value.push_str(param_value);
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame_parser::Opcode;
+
+    /// Appends its tag to every outgoing message and strips it from incoming ones,
+    /// so we can tell in which order extensions were applied.
+    struct Tag {
+        name: &'static str,
+        reserved_bits: u8,
+    }
+
+    impl Extension for Tag {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn negotiate(&mut self, offer: &ExtensionOffer) -> Option<ExtensionParams> {
+            Some(offer.params.clone())
+        }
+
+        fn reserved_bits(&self) -> u8 {
+            self.reserved_bits
+        }
+
+        fn decode_message(&mut self, mut message: DataFrame) -> Result<DataFrame, Error> {
+            let payload = message.payload_bytes.as_mut().unwrap();
+            assert!(payload.ends_with(self.name.as_bytes()));
+            payload.truncate(payload.len() - self.name.len());
+            Ok(message)
+        }
+
+        fn encode_message(&mut self, mut message: DataFrame) -> Result<DataFrame, Error> {
+            message
+                .payload_bytes
+                .get_or_insert_with(Vec::new)
+                .extend_from_slice(self.name.as_bytes());
+            Ok(message)
+        }
+    }
+
+    fn tag(name: &'static str, reserved_bits: u8) -> Box<dyn Extension> {
+        Box::new(Tag {
+            name,
+            reserved_bits,
+        })
+    }
+
+    fn offer(element: &str) -> ExtensionOffer {
+        ExtensionOffer::parse(element)
+    }
+
+    #[test]
+    fn it_accepts_offers_in_the_clients_order() {
+        let (chain, header_values) = ExtensionChain::negotiate(
+            vec![tag("a", 0), tag("b", 0), tag("unused", 0)],
+            &[offer("b; x=1; y"), offer("unknown"), offer("a")],
+        );
+
+        assert_eq!(header_values, vec!["b; x=1; y", "a"]);
+        assert_eq!(chain.extensions.len(), 2);
+    }
+
+    #[test]
+    fn it_accepts_each_extension_once() {
+        let (_, header_values) =
+            ExtensionChain::negotiate(vec![tag("a", 0)], &[offer("a; x=1"), offer("a; x=2")]);
+
+        assert_eq!(header_values, vec!["a; x=1"]);
+    }
+
+    #[test]
+    fn it_skips_extensions_whose_reserved_bits_are_taken() {
+        let (chain, header_values) = ExtensionChain::negotiate(
+            vec![
+                tag("a", 0b01000000),
+                tag("b", 0b01000000),
+                tag("c", 0b00100000),
+            ],
+            &[offer("a"), offer("b"), offer("c")],
+        );
+
+        assert_eq!(header_values, vec!["a", "c"]);
+        assert_eq!(chain.reserved_bits(), 0b01100000);
+    }
+
+    #[test]
+    fn it_encodes_in_order_and_decodes_in_reverse() {
+        let (mut chain, _) =
+            ExtensionChain::negotiate(vec![tag("a", 0), tag("b", 0)], &[offer("a"), offer("b")]);
+
+        let encoded = chain
+            .encode_message(DataFrame::new(true, Opcode::Text, b"Hi".to_vec()))
+            .unwrap();
+        assert_eq!(encoded.payload_bytes, Some(b"Hiab".to_vec()));
+
+        let decoded = chain.decode_message(encoded).unwrap();
+        assert_eq!(decoded.payload_bytes, Some(b"Hi".to_vec()));
+    }
+}
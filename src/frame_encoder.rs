@@ -0,0 +1,282 @@
+use crate::frame_parser::{DataFrame, Opcode};
+use crate::message::Message;
+
+// 2 bytes of header, 8 bytes of extended payload length and 4 bytes of masking key.
+static MAX_HEADER_LENGTH: usize = 14; // bytes
+
+/// How the payload of outgoing frames should be masked.
+///
+/// Clients must mask every frame they send, while servers must never mask theirs.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Masking {
+    None,
+
+    // Mask every frame with a fresh random key.
+    Random,
+
+    // Mask every frame with the given key. Mostly useful for tests.
+    Key([u8; 4]),
+}
+
+pub struct FrameEncoder {
+    masking: Masking,
+
+    // Messages with a payload larger than this are split into several frames.
+    fragment_size: Option<usize>,
+}
+
+impl Default for FrameEncoder {
+    fn default() -> Self {
+        FrameEncoder::new()
+    }
+}
+
+impl FrameEncoder {
+    /// Creates an encoder that neither masks nor fragments, which is what a server wants.
+    pub fn new() -> FrameEncoder {
+        FrameEncoder {
+            masking: Masking::None,
+            fragment_size: None,
+        }
+    }
+
+    pub fn set_masking(&mut self, masking: Masking) {
+        self.masking = masking;
+    }
+
+    /// Split messages into frames carrying at most `fragment_size` bytes of payload.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `fragment_size` is zero.
+    pub fn set_fragment_size(&mut self, fragment_size: usize) {
+        assert!(fragment_size > 0);
+
+        self.fragment_size = Some(fragment_size);
+    }
+
+    pub fn encode_frame(&self, frame: &DataFrame) -> Vec<u8> {
+        let masking_key = match self.masking {
+            Masking::None => None,
+            Masking::Random => Some(rand::random::<[u8; 4]>()),
+            Masking::Key(key) => Some(key),
+        };
+
+        frame.encode(masking_key)
+    }
+
+    /// Encodes a text or binary message, fragmenting it if a fragment size has been set.
+    pub fn encode_message(&self, message: &Message) -> Vec<u8> {
+        let frames = self.fragment(message.to_frame());
+        let mut bytes = Vec::new();
+
+        for frame in frames {
+            bytes.append(&mut self.encode_frame(&frame));
+        }
+
+        bytes
+    }
+
+    /// Splits a whole message into frames of at most the fragment size. The RSV bits
+    /// of the message are kept on the first frame, since they describe the whole message.
+    pub fn fragment(&self, message: DataFrame) -> Vec<DataFrame> {
+        let payload = message.payload_bytes.as_deref().unwrap_or(&[]);
+
+        let fragment_size = match self.fragment_size {
+            Some(fragment_size) if payload.len() > fragment_size => fragment_size,
+            _ => return vec![message],
+        };
+
+        let fragment_count = payload.len().div_ceil(fragment_size);
+        let mut frames = Vec::with_capacity(fragment_count);
+
+        for (index, fragment) in payload.chunks(fragment_size).enumerate() {
+            let is_last = index == fragment_count - 1;
+            let frame = if index == 0 {
+                let mut first_frame = DataFrame::new(is_last, message.opcode, fragment.to_vec());
+                first_frame.rsv1 = message.rsv1;
+                first_frame.rsv2 = message.rsv2;
+                first_frame.rsv3 = message.rsv3;
+                first_frame
+            } else {
+                DataFrame::new(is_last, Opcode::Continuation, fragment.to_vec())
+            };
+            frames.push(frame);
+        }
+
+        frames
+    }
+}
+
+impl DataFrame {
+    /// Serializes the frame, masking the payload if a masking key is given.
+    pub fn encode(&self, masking_key: Option<[u8; 4]>) -> Vec<u8> {
+        let payload: &[u8] = self.payload_bytes.as_deref().unwrap_or(&[]);
+        let mut bytes = Vec::with_capacity(payload.len() + MAX_HEADER_LENGTH);
+
+        let mut first_byte = self.opcode.as_u8();
+        if self.fin {
+            first_byte |= 0b10000000;
+        }
+        if self.rsv1 {
+            first_byte |= 0b01000000;
+        }
+        if self.rsv2 {
+            first_byte |= 0b00100000;
+        }
+        if self.rsv3 {
+            first_byte |= 0b00010000;
+        }
+        bytes.push(first_byte);
+
+        let mask_bit = if masking_key.is_some() { 0b10000000 } else { 0 };
+
+        // Use the shortest of the three length forms that can hold the payload length
+        let payload_length = payload.len();
+        if payload_length < 126 {
+            bytes.push(mask_bit | payload_length as u8);
+        } else if payload_length <= u16::MAX as usize {
+            bytes.push(mask_bit | 126);
+            bytes.extend_from_slice(&(payload_length as u16).to_be_bytes());
+        } else {
+            bytes.push(mask_bit | 127);
+            bytes.extend_from_slice(&(payload_length as u64).to_be_bytes());
+        }
+
+        match masking_key {
+            Some(masking_key) => {
+                bytes.extend_from_slice(&masking_key);
+                bytes.extend(
+                    payload
+                        .iter()
+                        .enumerate()
+                        .map(|(index, byte)| byte ^ masking_key[index % 4]),
+                );
+            }
+            None => bytes.extend_from_slice(payload),
+        }
+
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame_parser::FrameParser;
+
+    fn parse(mut bytes: Vec<u8>) -> Vec<DataFrame> {
+        let mut frames: Vec<DataFrame> = Vec::new();
+        let mut parser = FrameParser::new();
+        parser.set_allowed_reserved_bits(0b01000000);
+        parser.receive(&mut bytes, &mut frames).unwrap();
+        frames
+    }
+
+    #[test]
+    fn it_encodes_empty_ping_frame() {
+        let frame = DataFrame::new(true, Opcode::Ping, Vec::new());
+
+        assert_eq!(frame.encode(None), vec![0b10001001, 0b00000000]);
+    }
+
+    #[test]
+    fn it_encodes_reserved_bits() {
+        let mut frame = DataFrame::new(false, Opcode::Text, Vec::new());
+        frame.rsv1 = true;
+        frame.rsv3 = true;
+
+        assert_eq!(frame.encode(None), vec![0b01010001, 0b00000000]);
+    }
+
+    #[test]
+    fn it_picks_the_shortest_payload_length_form() {
+        let short = DataFrame::new(true, Opcode::Binary, vec![7; 125]).encode(None);
+        assert_eq!(short[..2], [0b10000010, 125]);
+        assert_eq!(short.len(), 2 + 125);
+
+        let extended = DataFrame::new(true, Opcode::Binary, vec![7; 126]).encode(None);
+        assert_eq!(extended[..4], [0b10000010, 126, 0x00, 0x7E]);
+        assert_eq!(extended.len(), 4 + 126);
+
+        let long_extended = DataFrame::new(true, Opcode::Binary, vec![7; 65536]).encode(None);
+        assert_eq!(
+            long_extended[..10],
+            [0b10000010, 127, 0, 0, 0, 0, 0, 0x01, 0x00, 0x00]
+        );
+        assert_eq!(long_extended.len(), 10 + 65536);
+    }
+
+    #[test]
+    fn it_masks_payload_with_given_key() {
+        let mask = [0b10110101, 0b00000000, 0b11111111, 0b10110000];
+        let frame = DataFrame::new(true, Opcode::Text, b"Hello".to_vec());
+
+        let bytes = frame.encode(Some(mask));
+
+        assert_eq!(bytes[..2], [0b10000001, 0b10000101]);
+        assert_eq!(bytes[2..6], mask);
+        assert_eq!(bytes[6] ^ mask[0], b'H');
+        assert_eq!(parse(bytes), vec![frame]);
+    }
+
+    #[test]
+    fn it_roundtrips_through_the_parser_with_random_masking() {
+        let mut encoder = FrameEncoder::new();
+        encoder.set_masking(Masking::Random);
+        let payload: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+
+        let bytes = encoder.encode_message(&Message::Binary(payload.clone()));
+
+        assert_eq!(bytes[1] & 0b10000000, 0b10000000);
+        assert_eq!(
+            parse(bytes),
+            vec![DataFrame::new(true, Opcode::Binary, payload)]
+        );
+    }
+
+    #[test]
+    fn it_splits_large_messages_into_fragments() {
+        let mut encoder = FrameEncoder::new();
+        encoder.set_fragment_size(4);
+
+        let bytes = encoder.encode_message(&Message::Text("Hello world".to_owned()));
+
+        assert_eq!(
+            parse(bytes),
+            vec![
+                DataFrame::new(false, Opcode::Text, b"Hell".to_vec()),
+                DataFrame::new(false, Opcode::Continuation, b"o wo".to_vec()),
+                DataFrame::new(true, Opcode::Continuation, b"rld".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_does_not_fragment_messages_that_fit_in_one_frame() {
+        let mut encoder = FrameEncoder::new();
+        encoder.set_fragment_size(5);
+
+        let bytes = encoder.encode_message(&Message::Text("Hello".to_owned()));
+
+        assert_eq!(
+            parse(bytes),
+            vec![DataFrame::new(true, Opcode::Text, b"Hello".to_vec())]
+        );
+    }
+
+    #[test]
+    fn it_keeps_reserved_bits_on_the_first_fragment_only() {
+        let mut encoder = FrameEncoder::new();
+        encoder.set_fragment_size(3);
+        let mut message = DataFrame::new(true, Opcode::Binary, vec![1, 2, 3, 4, 5, 6, 7]);
+        message.rsv1 = true;
+
+        let frames = encoder.fragment(message);
+
+        assert_eq!(frames.len(), 3);
+        assert!(frames[0].rsv1);
+        assert!(frames[1..].iter().all(|frame| !frame.rsv1));
+        assert!(frames[2].fin);
+    }
+}
@@ -1,19 +1,39 @@
 use std::cmp::min;
-use std::iter::Take;
+use std::convert::TryInto;
 
 #[derive(PartialEq, Debug, Clone, Copy)]
-enum Opcode {
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
     Ping,
     Pong,
-    Unknown,
 }
 
 impl Opcode {
-    fn from_u8(value: u8) -> Opcode {
+    /// Opcodes 0x3-0x7 and 0xB-0xF are reserved for future use by RFC 6455,
+    /// so receiving one of them is a protocol error.
+    fn from_u8(value: u8) -> Result<Opcode, ParseError> {
         match value {
-            0x09 => Opcode::Ping,
-            0x0A => Opcode::Pong,
-            _ => Opcode::Unknown,
+            0x00 => Ok(Opcode::Continuation),
+            0x01 => Ok(Opcode::Text),
+            0x02 => Ok(Opcode::Binary),
+            0x08 => Ok(Opcode::Close),
+            0x09 => Ok(Opcode::Ping),
+            0x0A => Ok(Opcode::Pong),
+            _ => Err(ParseError::ReservedOpcode(value)),
+        }
+    }
+
+    pub fn as_u8(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x00,
+            Opcode::Text => 0x01,
+            Opcode::Binary => 0x02,
+            Opcode::Close => 0x08,
+            Opcode::Ping => 0x09,
+            Opcode::Pong => 0x0A,
         }
     }
 }
@@ -27,19 +47,55 @@ enum PayloadLengthType {
 
 impl PayloadLengthType {
     fn from_number(value: u8) -> PayloadLengthType {
-        PayloadLengthType::Normal
+        match value {
+            126 => PayloadLengthType::Extended,
+            127 => PayloadLengthType::LongExtended,
+            _ => PayloadLengthType::Normal,
+        }
+    }
+
+    /// The number of bytes following the 7-bit length that hold the actual payload length.
+    fn extended_length_bytes(&self) -> usize {
+        match self {
+            PayloadLengthType::Normal => 0,
+            PayloadLengthType::Extended => 2,
+            PayloadLengthType::LongExtended => 8,
+        }
     }
 }
 
 #[derive(PartialEq, Debug)]
 pub struct DataFrame {
-    fin: bool,
-    opcode: Opcode,
-    payload_bytes: Option<Vec<u8>>,
+    pub fin: bool,
+    pub rsv1: bool,
+    pub rsv2: bool,
+    pub rsv3: bool,
+    pub opcode: Opcode,
+    pub payload_bytes: Option<Vec<u8>>,
+}
+
+impl DataFrame {
+    pub fn new(fin: bool, opcode: Opcode, payload: Vec<u8>) -> DataFrame {
+        DataFrame {
+            fin,
+            rsv1: false,
+            rsv2: false,
+            rsv3: false,
+            opcode,
+            payload_bytes: if payload.is_empty() {
+                None
+            } else {
+                Some(payload)
+            },
+        }
+    }
 }
 
 struct UnfinishedDataFrame {
     fin: bool,
+    rsv1: bool,
+    rsv2: bool,
+    rsv3: bool,
     opcode: Opcode,
     payload_length_type: Option<PayloadLengthType>,
     payload_length: Option<u64>,
@@ -52,6 +108,27 @@ pub trait DataFrameReceiver {
     fn receive(&mut self, frame: DataFrame);
 }
 
+impl DataFrameReceiver for Vec<DataFrame> {
+    fn receive(&mut self, frame: DataFrame) {
+        self.push(frame);
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum ParseError {
+    // The most significant bit of a 64-bit payload length must be 0.
+    InvalidPayloadLength,
+
+    // The frame used one of the opcodes reserved for future use.
+    ReservedOpcode(u8),
+
+    // The frame set RSV bits that no negotiated extension has claimed.
+    UnexpectedReservedBits(u8),
+
+    // The payload length is larger than the maximum we accept.
+    PayloadTooLarge,
+}
+
 #[derive(PartialEq, Debug)]
 enum ParserState {
     // We are waiting for the first byte of the frame.
@@ -60,7 +137,7 @@ enum ParserState {
     // We are waiting for the payload length byte of the frame.
     PayloadLength,
 
-    // Optional state that happens if frame.payload_length > 126. Here, we wait for all the bytes to finish the extended payload length.
+    // Optional state that happens if frame.payload_length >= 126. Here, we wait for all the bytes to finish the extended payload length.
     ExtendedPayloadLength,
 
     // We are reading the bytes of the masking key.
@@ -70,57 +147,99 @@ enum ParserState {
     Payload,
 }
 
-pub struct FrameParser<'a> {
+pub struct FrameParser {
     unfinished_frame: Option<UnfinishedDataFrame>,
     state: ParserState,
 
     // A buffer used when reading the bytes
     byte_buffer: Option<Vec<u8>>,
 
-    frame_receiver: Option<&'a mut dyn DataFrameReceiver>,
+    // The RSV bits (in their position in the first byte) that extensions have claimed.
+    // Frames with any other RSV bit set are rejected.
+    allowed_reserved_bits: u8,
+
+    // Frames announcing a longer payload are rejected before we start buffering it.
+    max_payload_length: Option<u64>,
 }
 
-static PING_FRAME: [u8; 2] = [0b10001001, 0b00000000];
 static MASKING_KEY_LENGTH: usize = 4; // bytes
+static RESERVED_BITS_MASK: u8 = 0b01110000;
+static MAX_INITIAL_PAYLOAD_CAPACITY: usize = 64 * 1024; // bytes
+
+impl Default for FrameParser {
+    fn default() -> Self {
+        FrameParser::new()
+    }
+}
 
-impl<'a> FrameParser<'a> {
-    pub fn new() -> FrameParser<'a> {
+impl FrameParser {
+    pub fn new() -> FrameParser {
         FrameParser {
             unfinished_frame: None,
             byte_buffer: None,
-            frame_receiver: None,
+            allowed_reserved_bits: 0,
+            max_payload_length: None,
             state: ParserState::FirstByte,
         }
     }
 
-    fn set_frame_receiver(&mut self, frame_receiver: &'a mut dyn DataFrameReceiver) {
-        self.frame_receiver = Some(frame_receiver);
+    /// Allow frames with these RSV bits set, because an extension gives them a meaning.
+    /// The bits are given in their position in the first byte of a frame, so RSV1 is `0b01000000`.
+    pub fn set_allowed_reserved_bits(&mut self, allowed_reserved_bits: u8) {
+        self.allowed_reserved_bits = allowed_reserved_bits & RESERVED_BITS_MASK;
     }
 
+    pub fn set_max_payload_length(&mut self, max_payload_length: u64) {
+        self.max_payload_length = Some(max_payload_length);
+    }
+
+    /// Parses `bytes` and hands every completed frame to `frame_receiver`.
+    ///
     /// Note that `bytes` is mutable because the bytes are consumed.
     /// If we want to avoid consuming the input bytes, we would have to clone
     /// the entire vector of bytes.
-    pub fn receive(&mut self, mut bytes: &mut Vec<u8>) {
-        self.parse_bytes(&mut bytes);
+    pub fn receive(
+        &mut self,
+        bytes: &mut Vec<u8>,
+        frame_receiver: &mut dyn DataFrameReceiver,
+    ) -> Result<(), ParseError> {
+        self.parse_bytes(bytes, frame_receiver)
     }
 
-    fn parse_bytes(&mut self, mut bytes: &mut Vec<u8>) {
-        while bytes.len() > 0 {
+    fn parse_bytes(
+        &mut self,
+        bytes: &mut Vec<u8>,
+        frame_receiver: &mut dyn DataFrameReceiver,
+    ) -> Result<(), ParseError> {
+        while !bytes.is_empty() {
             match self.state {
-                ParserState::FirstByte => self.parse_first_byte(&mut bytes),
-                ParserState::PayloadLength => self.parse_payload_length(&mut bytes),
-                ParserState::ExtendedPayloadLength => todo!(),
-                ParserState::MaskingKey => self.parse_masking_key(&mut bytes),
-                ParserState::Payload => self.parse_payload(&mut bytes),
+                ParserState::FirstByte => self.parse_first_byte(bytes)?,
+                ParserState::PayloadLength => self.parse_payload_length(bytes, frame_receiver),
+                ParserState::ExtendedPayloadLength => {
+                    self.parse_extended_payload_length(bytes, frame_receiver)?
+                }
+                ParserState::MaskingKey => self.parse_masking_key(bytes, frame_receiver),
+                ParserState::Payload => self.parse_payload(bytes, frame_receiver),
             };
         }
+
+        Ok(())
     }
 
-    fn parse_first_byte(&mut self, bytes: &mut Vec<u8>) {
+    fn parse_first_byte(&mut self, bytes: &mut Vec<u8>) -> Result<(), ParseError> {
         let first_byte = consume_one(bytes);
+
+        let reserved_bits = first_byte & RESERVED_BITS_MASK;
+        if reserved_bits & !self.allowed_reserved_bits != 0 {
+            return Err(ParseError::UnexpectedReservedBits(reserved_bits >> 4));
+        }
+
         self.unfinished_frame = Some(UnfinishedDataFrame {
             fin: first_byte & 0b10000000 == 0b10000000,
-            opcode: Opcode::from_u8(first_byte & 0b00001111),
+            rsv1: first_byte & 0b01000000 == 0b01000000,
+            rsv2: first_byte & 0b00100000 == 0b00100000,
+            rsv3: first_byte & 0b00010000 == 0b00010000,
+            opcode: Opcode::from_u8(first_byte & 0b00001111)?,
             payload_length_type: None,
             payload_length: None,
             is_masked: false,
@@ -129,30 +248,104 @@ impl<'a> FrameParser<'a> {
         });
 
         self.state = ParserState::PayloadLength;
+
+        Ok(())
     }
 
-    fn parse_payload_length(&mut self, bytes: &mut Vec<u8>) {
+    fn parse_payload_length(
+        &mut self,
+        bytes: &mut Vec<u8>,
+        frame_receiver: &mut dyn DataFrameReceiver,
+    ) {
         let byte = consume_one(bytes);
-        let mut unfinished_frame = self.unfinished_frame.as_mut().unwrap();
+        let unfinished_frame = self.unfinished_frame.as_mut().unwrap();
 
         unfinished_frame.is_masked = byte & 0b10000000 == 0b10000000;
 
         let payload_length = byte & 0b01111111;
-        unfinished_frame.payload_length_type = Some(PayloadLengthType::from_number(payload_length));
-        unfinished_frame.payload_length = Some(payload_length as u64);
+        let payload_length_type = PayloadLengthType::from_number(payload_length);
+
+        if payload_length_type == PayloadLengthType::Normal {
+            unfinished_frame.payload_length = Some(payload_length as u64);
+            unfinished_frame.payload_length_type = Some(payload_length_type);
+            self.begin_masking_key_or_payload(frame_receiver);
+        } else {
+            unfinished_frame.payload_length_type = Some(payload_length_type);
+            self.state = ParserState::ExtendedPayloadLength;
+        }
+    }
+
+    fn parse_extended_payload_length(
+        &mut self,
+        bytes: &mut Vec<u8>,
+        frame_receiver: &mut dyn DataFrameReceiver,
+    ) -> Result<(), ParseError> {
+        let unfinished_frame = self.unfinished_frame.as_mut().unwrap();
+        let length_bytes_needed = unfinished_frame
+            .payload_length_type
+            .as_ref()
+            .unwrap()
+            .extended_length_bytes();
+
+        if self.byte_buffer.is_none() {
+            self.byte_buffer = Some(Vec::with_capacity(length_bytes_needed));
+        }
+        let length_bytes = self.byte_buffer.as_mut().unwrap();
 
-        if unfinished_frame.payload_length == Some(0) {
-            self.finish_frame();
-        } else if unfinished_frame.is_masked {
+        // The length may be split across several calls to receive,
+        // so we only take the bytes we are still missing.
+        let bytes_to_take = min(length_bytes_needed - length_bytes.len(), bytes.len());
+        length_bytes.append(&mut consume(bytes, bytes_to_take));
+
+        if length_bytes.len() < length_bytes_needed {
+            return Ok(());
+        }
+
+        // The extended payload length is in network byte order (big-endian)
+        let payload_length = match length_bytes_needed {
+            2 => u16::from_be_bytes(length_bytes[..].try_into().unwrap()) as u64,
+            _ => u64::from_be_bytes(length_bytes[..].try_into().unwrap()),
+        };
+        self.byte_buffer = None;
+
+        if payload_length & (1 << 63) != 0 {
+            return Err(ParseError::InvalidPayloadLength);
+        }
+
+        if self
+            .max_payload_length
+            .is_some_and(|max_payload_length| payload_length > max_payload_length)
+        {
+            return Err(ParseError::PayloadTooLarge);
+        }
+
+        unfinished_frame.payload_length = Some(payload_length);
+        self.begin_masking_key_or_payload(frame_receiver);
+
+        Ok(())
+    }
+
+    /// Moves on to whatever follows the payload length, finishing the frame right away
+    /// if there are no more bytes to read for it.
+    fn begin_masking_key_or_payload(&mut self, frame_receiver: &mut dyn DataFrameReceiver) {
+        let unfinished_frame = self.unfinished_frame.as_ref().unwrap();
+
+        if unfinished_frame.is_masked && unfinished_frame.masking_key.is_none() {
             self.state = ParserState::MaskingKey;
+        } else if unfinished_frame.payload_length == Some(0) {
+            self.finish_frame(frame_receiver);
         } else {
             self.state = ParserState::Payload;
-        };
+        }
     }
 
-    fn parse_masking_key(&mut self, bytes: &mut Vec<u8>) {
+    fn parse_masking_key(
+        &mut self,
+        bytes: &mut Vec<u8>,
+        frame_receiver: &mut dyn DataFrameReceiver,
+    ) {
         let unfinished_frame = self.unfinished_frame.as_mut().unwrap();
-        if unfinished_frame.masking_key == None {
+        if unfinished_frame.masking_key.is_none() {
             unfinished_frame.masking_key = Some(Vec::with_capacity(MASKING_KEY_LENGTH))
         };
         let masking_key = unfinished_frame.masking_key.as_mut().unwrap();
@@ -165,22 +358,20 @@ impl<'a> FrameParser<'a> {
         let mut masking_key_bytes = consume(bytes, bytes_to_take);
         masking_key.append(&mut masking_key_bytes);
 
-        if masking_key.len() == 4 {
-            self.state = ParserState::Payload;
+        if masking_key.len() == MASKING_KEY_LENGTH {
+            self.begin_masking_key_or_payload(frame_receiver);
         }
     }
 
-    fn parse_payload(&mut self, bytes: &mut Vec<u8>) {
+    fn parse_payload(&mut self, bytes: &mut Vec<u8>, frame_receiver: &mut dyn DataFrameReceiver) {
         let unfinished_frame = self.unfinished_frame.as_mut().unwrap();
         let payload_length = unfinished_frame.payload_length.unwrap();
 
-        if payload_length == 0 {
-            self.finish_frame();
-            return;
-        }
-
-        if unfinished_frame.payload_bytes == None {
-            unfinished_frame.payload_bytes = Some(Vec::with_capacity(payload_length as usize));
+        if unfinished_frame.payload_bytes.is_none() {
+            // Don't trust the announced length for the initial allocation, since
+            // the peer can claim up to 2^63 bytes.
+            let capacity = min(payload_length, MAX_INITIAL_PAYLOAD_CAPACITY as u64) as usize;
+            unfinished_frame.payload_bytes = Some(Vec::with_capacity(capacity));
         }
         let unfinished_frame_payload = unfinished_frame.payload_bytes.as_mut().unwrap();
 
@@ -207,30 +398,24 @@ impl<'a> FrameParser<'a> {
 
         // Check if bytes contained the rest of the payload
         if bytes_to_take == bytes_left_of_payload {
-            self.finish_frame();
+            self.finish_frame(frame_receiver);
         }
     }
 
-    pub fn finish_frame(&mut self) {
-        if let Some(frame_receiver) = &mut self.frame_receiver {
-            if let Some(finished_frame) = &self.unfinished_frame {
-                frame_receiver.receive(DataFrame {
-                    fin: finished_frame.fin,
-                    opcode: finished_frame.opcode,
-                    // We're making a clone of the entire payload here
-                    // not sure if that is a good idea...
-                    payload_bytes: finished_frame.payload_bytes.clone(),
-                });
-            }
-        };
+    fn finish_frame(&mut self, frame_receiver: &mut dyn DataFrameReceiver) {
+        if let Some(finished_frame) = self.unfinished_frame.take() {
+            frame_receiver.receive(DataFrame {
+                fin: finished_frame.fin,
+                rsv1: finished_frame.rsv1,
+                rsv2: finished_frame.rsv2,
+                rsv3: finished_frame.rsv3,
+                opcode: finished_frame.opcode,
+                payload_bytes: finished_frame.payload_bytes,
+            });
+        }
 
-        self.unfinished_frame = None;
         self.state = ParserState::FirstByte;
     }
-
-    pub fn create_ping_frame() -> &'static [u8] {
-        return &PING_FRAME;
-    }
 }
 
 fn consume_one<T: Copy>(vec: &mut Vec<T>) -> T {
@@ -257,7 +442,7 @@ mod test {
         }
     }
 
-    impl<'a> DataFrameReceiver for TestFrameReceiver {
+    impl DataFrameReceiver for TestFrameReceiver {
         fn receive(&mut self, frame: DataFrame) {
             self.received_frames.push(frame)
         }
@@ -272,14 +457,18 @@ mod test {
 
         let mut frame_receiver = TestFrameReceiver::new();
         let mut frame_parser = FrameParser::new();
-        frame_parser.set_frame_receiver(&mut frame_receiver);
 
-        frame_parser.parse_bytes(&mut pong_frame);
+        frame_parser
+            .parse_bytes(&mut pong_frame, &mut frame_receiver)
+            .unwrap();
 
         assert_eq!(
             frame_receiver.received_frames,
             vec![DataFrame {
                 fin: true,
+                rsv1: false,
+                rsv2: false,
+                rsv3: false,
                 opcode: Opcode::Pong,
                 payload_bytes: None,
             }]
@@ -295,15 +484,21 @@ mod test {
 
         let mut frame_receiver = TestFrameReceiver::new();
         let mut frame_parser = FrameParser::new();
-        frame_parser.set_frame_receiver(&mut frame_receiver);
 
-        frame_parser.parse_bytes(&mut pong_bytes_1);
-        frame_parser.parse_bytes(&mut pong_bytes_2);
+        frame_parser
+            .parse_bytes(&mut pong_bytes_1, &mut frame_receiver)
+            .unwrap();
+        frame_parser
+            .parse_bytes(&mut pong_bytes_2, &mut frame_receiver)
+            .unwrap();
 
         assert_eq!(
             frame_receiver.received_frames,
             vec![DataFrame {
                 fin: true,
+                rsv1: false,
+                rsv2: false,
+                rsv3: false,
                 opcode: Opcode::Pong,
                 payload_bytes: None,
             }]
@@ -317,21 +512,30 @@ mod test {
 
         let mut frame_parser = FrameParser::new();
         let mut frame_receiver = TestFrameReceiver::new();
-        frame_parser.set_frame_receiver(&mut frame_receiver);
 
-        frame_parser.parse_bytes(&mut ping_frame);
-        frame_parser.parse_bytes(&mut pong_frame);
+        frame_parser
+            .parse_bytes(&mut ping_frame, &mut frame_receiver)
+            .unwrap();
+        frame_parser
+            .parse_bytes(&mut pong_frame, &mut frame_receiver)
+            .unwrap();
 
         assert_eq!(
             frame_receiver.received_frames,
             vec![
                 DataFrame {
                     fin: true,
+                    rsv1: false,
+                    rsv2: false,
+                    rsv3: false,
                     opcode: Opcode::Ping,
                     payload_bytes: None,
                 },
                 DataFrame {
                     fin: true,
+                    rsv1: false,
+                    rsv2: false,
+                    rsv3: false,
                     opcode: Opcode::Pong,
                     payload_bytes: None,
                 }
@@ -346,14 +550,18 @@ mod test {
 
         let mut frame_parser = FrameParser::new();
         let mut frame_receiver = TestFrameReceiver::new();
-        frame_parser.set_frame_receiver(&mut frame_receiver);
 
-        frame_parser.parse_bytes(&mut frame_with_short_payload);
+        frame_parser
+            .parse_bytes(&mut frame_with_short_payload, &mut frame_receiver)
+            .unwrap();
         assert_eq!(
             frame_receiver.received_frames,
             vec![DataFrame {
                 fin: true,
-                opcode: Opcode::Unknown,
+                rsv1: false,
+                rsv2: false,
+                rsv3: false,
+                opcode: Opcode::Text,
                 payload_bytes: Some(payload),
             }],
         );
@@ -379,19 +587,241 @@ mod test {
 
         let mut frame_parser = FrameParser::new();
         let mut frame_receiver = TestFrameReceiver::new();
-        frame_parser.set_frame_receiver(&mut frame_receiver);
 
-        frame_parser.parse_bytes(&mut frame_with_masked_payload);
+        frame_parser
+            .parse_bytes(&mut frame_with_masked_payload, &mut frame_receiver)
+            .unwrap();
+
+        assert_eq!(
+            frame_receiver.received_frames,
+            vec![DataFrame {
+                fin: true,
+                rsv1: false,
+                rsv2: false,
+                rsv3: false,
+                opcode: Opcode::Text,
+                payload_bytes: Some(payload),
+            }],
+        );
+    }
+
+    #[test]
+    fn it_parses_16_bit_extended_payload_length() {
+        let payload: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let mut frame = [vec![0b10000010, 126, 0x01, 0x2C], payload.clone()].concat();
+
+        let mut frame_parser = FrameParser::new();
+        let mut frame_receiver = TestFrameReceiver::new();
+
+        frame_parser
+            .parse_bytes(&mut frame, &mut frame_receiver)
+            .unwrap();
+
+        assert_eq!(
+            frame_receiver.received_frames,
+            vec![DataFrame {
+                fin: true,
+                rsv1: false,
+                rsv2: false,
+                rsv3: false,
+                opcode: Opcode::Binary,
+                payload_bytes: Some(payload),
+            }],
+        );
+    }
+
+    #[test]
+    fn it_parses_64_bit_extended_payload_length() {
+        let payload: Vec<u8> = (0..70_000).map(|i| i as u8).collect();
+        let mut frame = [
+            vec![0b10000010, 127, 0, 0, 0, 0, 0, 0x01, 0x11, 0x70],
+            payload.clone(),
+        ]
+        .concat();
+
+        let mut frame_parser = FrameParser::new();
+        let mut frame_receiver = TestFrameReceiver::new();
+
+        frame_parser
+            .parse_bytes(&mut frame, &mut frame_receiver)
+            .unwrap();
+
+        assert_eq!(
+            frame_receiver.received_frames,
+            vec![DataFrame {
+                fin: true,
+                rsv1: false,
+                rsv2: false,
+                rsv3: false,
+                opcode: Opcode::Binary,
+                payload_bytes: Some(payload),
+            }],
+        );
+    }
+
+    #[test]
+    fn it_parses_extended_payload_length_split_across_receives() {
+        let mask = [0b10110101, 0b00000000, 0b11111111, 0b10110000];
+        let payload: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let masked_payload: Vec<u8> = payload
+            .iter()
+            .enumerate()
+            .map(|(index, byte)| byte ^ mask[index % 4])
+            .collect();
+
+        let mut frame_parser = FrameParser::new();
+        let mut frame_receiver = TestFrameReceiver::new();
+
+        frame_parser
+            .parse_bytes(&mut vec![0b10000010, 0b11111110, 0x00], &mut frame_receiver)
+            .unwrap();
+        frame_parser
+            .parse_bytes(
+                &mut [vec![0xC8], mask[..2].to_vec()].concat(),
+                &mut frame_receiver,
+            )
+            .unwrap();
+        frame_parser
+            .parse_bytes(
+                &mut [mask[2..].to_vec(), masked_payload[..100].to_vec()].concat(),
+                &mut frame_receiver,
+            )
+            .unwrap();
+        assert!(frame_receiver.received_frames.is_empty());
+
+        frame_parser
+            .parse_bytes(&mut masked_payload[100..].to_vec(), &mut frame_receiver)
+            .unwrap();
 
         assert_eq!(
             frame_receiver.received_frames,
             vec![DataFrame {
                 fin: true,
-                opcode: Opcode::Unknown,
+                rsv1: false,
+                rsv2: false,
+                rsv3: false,
+                opcode: Opcode::Binary,
                 payload_bytes: Some(payload),
             }],
         );
     }
 
-    // fn it_supports_payload_split_in_multiple_frames
+    #[test]
+    fn it_rejects_64_bit_payload_length_with_most_significant_bit_set() {
+        let mut frame = vec![0b10000010, 127, 0x80, 0, 0, 0, 0, 0, 0, 0x01];
+
+        let mut frame_parser = FrameParser::new();
+        let mut frame_receiver = TestFrameReceiver::new();
+
+        assert_eq!(
+            frame_parser.parse_bytes(&mut frame, &mut frame_receiver),
+            Err(ParseError::InvalidPayloadLength)
+        );
+        assert!(frame_receiver.received_frames.is_empty());
+    }
+
+    #[test]
+    fn it_rejects_payload_length_above_the_maximum() {
+        let mut frame = vec![0b10000010, 126, 0x01, 0x2C];
+
+        let mut frame_parser = FrameParser::new();
+        frame_parser.set_max_payload_length(299);
+        let mut frame_receiver = TestFrameReceiver::new();
+
+        assert_eq!(
+            frame_parser.parse_bytes(&mut frame, &mut frame_receiver),
+            Err(ParseError::PayloadTooLarge)
+        );
+    }
+
+    #[test]
+    fn it_parses_all_defined_opcodes() {
+        let mut frames = vec![
+            0b00000001, 0b00000000, // Text without fin
+            0b10000000, 0b00000000, // Continuation
+            0b10000010, 0b00000000, // Binary
+            0b10001000, 0b00000000, // Close
+            0b10001001, 0b00000000, // Ping
+            0b10001010, 0b00000000, // Pong
+        ];
+
+        let mut frame_parser = FrameParser::new();
+        let mut frame_receiver = TestFrameReceiver::new();
+
+        frame_parser
+            .parse_bytes(&mut frames, &mut frame_receiver)
+            .unwrap();
+
+        let opcodes: Vec<Opcode> = frame_receiver
+            .received_frames
+            .iter()
+            .map(|frame| frame.opcode)
+            .collect();
+        assert_eq!(
+            opcodes,
+            vec![
+                Opcode::Text,
+                Opcode::Continuation,
+                Opcode::Binary,
+                Opcode::Close,
+                Opcode::Ping,
+                Opcode::Pong
+            ]
+        );
+        assert!(!frame_receiver.received_frames[0].fin);
+    }
+
+    #[test]
+    fn it_rejects_reserved_opcodes() {
+        for opcode in (0x03..=0x07).chain(0x0B..=0x0F) {
+            let mut frame = vec![0b10000000 | opcode, 0b00000000];
+
+            let mut frame_parser = FrameParser::new();
+            let mut frame_receiver = TestFrameReceiver::new();
+
+            assert_eq!(
+                frame_parser.parse_bytes(&mut frame, &mut frame_receiver),
+                Err(ParseError::ReservedOpcode(opcode))
+            );
+        }
+    }
+
+    #[test]
+    fn it_rejects_unclaimed_reserved_bits() {
+        let mut frame = vec![0b11000001, 0b00000000];
+
+        let mut frame_parser = FrameParser::new();
+        let mut frame_receiver = TestFrameReceiver::new();
+
+        assert_eq!(
+            frame_parser.parse_bytes(&mut frame, &mut frame_receiver),
+            Err(ParseError::UnexpectedReservedBits(0b100))
+        );
+        assert!(frame_receiver.received_frames.is_empty());
+    }
+
+    #[test]
+    fn it_parses_claimed_reserved_bits() {
+        let mut frame = vec![0b11010001, 0b00000000];
+
+        let mut frame_parser = FrameParser::new();
+        frame_parser.set_allowed_reserved_bits(0b01010000);
+        let mut frame_receiver = TestFrameReceiver::new();
+
+        frame_parser
+            .parse_bytes(&mut frame, &mut frame_receiver)
+            .unwrap();
+
+        assert_eq!(
+            frame_receiver.received_frames,
+            vec![DataFrame {
+                fin: true,
+                rsv1: true,
+                rsv2: false,
+                rsv3: true,
+                opcode: Opcode::Text,
+                payload_bytes: None,
+            }],
+        );
+    }
 }
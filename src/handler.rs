@@ -0,0 +1,30 @@
+use crate::{
+    close::CloseFrame, error::Error, http::HttpUpgradeRequest, message::Message, WebSocket,
+};
+
+/// Callbacks for the traffic on a single connection.
+///
+/// Every method has an empty default implementation, so implementors only
+/// need to override the events they care about. The `socket` can be used to
+/// send messages back to the peer from inside the callbacks.
+pub trait WebSocketHandler {
+    /// Called once the opening handshake has completed.
+    fn on_open(&mut self, _socket: &mut WebSocket<'_>, _request: &HttpUpgradeRequest) {}
+
+    /// Called for every complete text or binary message.
+    fn on_message(&mut self, _socket: &mut WebSocket<'_>, _message: Message) {}
+
+    /// Called when the peer pings us. The pong has already been sent.
+    fn on_ping(&mut self, _socket: &mut WebSocket<'_>, _payload: &[u8]) {}
+
+    /// Called when the peer answers a ping. If it was a heartbeat ping,
+    /// `socket.round_trip_time()` has been updated.
+    fn on_pong(&mut self, _socket: &mut WebSocket<'_>, _payload: &[u8]) {}
+
+    /// Called when the connection is closed. If the peer went away without
+    /// sending a close frame, the code is `CloseCode::Abnormal`.
+    fn on_close(&mut self, _close_frame: CloseFrame) {}
+
+    /// Called when something goes wrong on the connection, right before it is closed.
+    fn on_error(&mut self, _error: Error) {}
+}
@@ -1,37 +1,712 @@
-use std::collections::HashMap;
+use crate::error::Error;
 
-enum Method {
-  Get,
+static MAX_HEADER_COUNT: usize = 64;
+static MAX_HEADER_LINE_LENGTH: usize = 1024; // bytes
+
+/// The header fields of a request, in the order they were sent.
+/// Names are matched case-insensitively.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Headers {
+  fields: Vec<(String, String)>,
+}
+
+impl Headers {
+  pub fn new() -> Headers {
+    Headers { fields: Vec::new() }
+  }
+
+  /// Adds a header. Names have to be tokens and values can't contain line breaks,
+  /// or the header is left out when the message is serialized.
+  pub fn insert(&mut self, name: &str, value: &str) {
+    self.fields.push((name.to_owned(), value.to_owned()));
+  }
+
+  /// The value of the header. If it was sent several times, the values are joined with ", ",
+  /// which is equivalent to a single comma-separated header.
+  pub fn get(&self, name: &str) -> Option<String> {
+    let values: Vec<&str> = self.get_all(name).collect();
+    if values.is_empty() {
+      None
+    } else {
+      Some(values.join(", "))
+    }
+  }
+
+  /// Every value sent for the header, one per header line.
+  pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    self.fields.iter()
+      .filter(move |(field_name, _)| field_name.eq_ignore_ascii_case(name))
+      .map(|(_, value)| value.as_str())
+  }
+
+  /// The comma-separated elements of every value sent for the header, with whitespace trimmed.
+  pub fn get_list<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    self.get_all(name)
+      .flat_map(|value| value.split(','))
+      .map(|element| element.trim_matches(is_whitespace))
+      .filter(|element| !element.is_empty())
+  }
+
+  /// Whether one of the comma-separated elements of the header equals `token`, ignoring case.
+  pub fn contains_token(&self, name: &str, token: &str) -> bool {
+    self.get_list(name).any(|element| element.eq_ignore_ascii_case(token))
+  }
+
+  pub fn len(&self) -> usize {
+    self.fields.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.fields.is_empty()
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+    self.fields.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+  }
+}
+
+/// One element of the `Sec-WebSocket-Extensions` header, like
+/// "permessage-deflate; client_max_window_bits=10".
+#[derive(PartialEq, Debug, Clone)]
+pub struct ExtensionOffer {
+  pub name: String,
+  // Parameters in the order they were sent. Parameters without a value have `None`.
+  pub params: Vec<(String, Option<String>)>,
+}
+
+impl ExtensionOffer {
+  pub fn parse(element: &str) -> ExtensionOffer {
+    let mut parts = element.split(';').map(|part| part.trim_matches(is_whitespace));
+    let name = parts.next().unwrap_or_default().to_owned();
+
+    let params = parts.filter(|part| !part.is_empty()).map(|param| {
+      let mut split_iter = param.splitn(2, '=');
+      let name = split_iter.next().unwrap_or_default().trim_matches(is_whitespace).to_owned();
+      // Values may be sent as quoted strings
+      let value = split_iter.next().map(|value| value.trim_matches(is_whitespace).trim_matches('"').to_owned());
+      (name, value)
+    }).collect();
+
+    ExtensionOffer { name, params }
+  }
 }
 
 #[derive(PartialEq, Debug)]
-pub struct HttpUpgradeRequest<'a> {
-  pub path: &'a str,
-  pub host: &'a str,
+pub struct HttpUpgradeRequest {
+  pub method: String,
+  // The request target, like "/chat?room=1".
+  pub path: String,
+  // The parameters of the query string, percent-decoded, in the order they were sent.
+  pub query: Vec<(String, String)>,
+  // The parameters captured by the server route the request matched, like "room" for "/rooms/:room".
+  pub path_params: Vec<(String, String)>,
+  pub http_version: String,
+  pub host: String,
+  // 0 if the header is missing or isn't a number.
   pub sec_websocket_version: u8,
-  pub sec_websocket_key: &'a str,
+  pub sec_websocket_key: String,
+  // The subprotocols the client offered, in its order of preference.
+  pub sec_websocket_protocols: Vec<String>,
+  // The extensions the client offered, in its order of preference.
+  pub sec_websocket_extensions: Vec<ExtensionOffer>,
+  pub headers: Headers,
 }
 
-impl HttpUpgradeRequest<'_> {
-  pub fn parse(message: &str) -> Result<HttpUpgradeRequest, &str> {
-    let headers: HashMap<_, _> = message.split("\r\n").skip(1).map(|line| {
-      let mut split_iter= line.split(": "); 
-      println!("{}", line);
-      (split_iter.next().unwrap(), split_iter.next().unwrap())
-    }).collect();
+impl HttpUpgradeRequest {
+  /// Parses the request line and headers of a request, without the empty line that ends it.
+  pub fn parse(message: &str) -> Result<HttpUpgradeRequest, Error> {
+    let mut lines = message.split("\r\n");
+
+    let request_line = lines.next().unwrap_or_default();
+    let mut request_line_parts = request_line.split(' ');
+    let (method, path, http_version) = match (request_line_parts.next(), request_line_parts.next(), request_line_parts.next(), request_line_parts.next()) {
+      (Some(method), Some(path), Some(http_version), None)
+        if !method.is_empty() && !path.is_empty() && http_version.starts_with("HTTP/") => (method, path, http_version),
+      _ => return Err(Error::Handshake(format!("malformed request line: {:?}", request_line))),
+    };
+
+    let headers = parse_headers(lines)?;
 
     let request = HttpUpgradeRequest {
-      path: "/",
-      host: "not the host",
-      sec_websocket_version: 0,
-      sec_websocket_key: headers.get("Sec-WebSocket-Key").unwrap()
+      method: method.to_owned(),
+      path: path.to_owned(),
+      query: parse_query(path),
+      path_params: Vec::new(),
+      http_version: http_version.to_owned(),
+      host: headers.get("Host").unwrap_or_default(),
+      sec_websocket_version: headers.get("Sec-WebSocket-Version").and_then(|version| version.parse().ok()).unwrap_or(0),
+      sec_websocket_key: headers.get("Sec-WebSocket-Key").unwrap_or_default(),
+      sec_websocket_protocols: headers.get_list("Sec-WebSocket-Protocol").map(|subprotocol| subprotocol.to_owned()).collect(),
+      sec_websocket_extensions: headers.get_list("Sec-WebSocket-Extensions").map(ExtensionOffer::parse).collect(),
+      headers,
     };
 
     Ok(request)
   }
+
+  /// The value of the first query parameter with this name.
+  pub fn query_param(&self, name: &str) -> Option<&str> {
+    self.query.iter().find(|(param_name, _)| param_name == name).map(|(_, value)| value.as_str())
+  }
+
+  /// The value of a parameter captured by the route, like `path_param("room")` for "/rooms/:room".
+  pub fn path_param(&self, name: &str) -> Option<&str> {
+    self.path_params.iter().find(|(param_name, _)| param_name == name).map(|(_, value)| value.as_str())
+  }
+
+  /// The origin of the page that opened the connection, if the client is a browser.
+  pub fn origin(&self) -> Option<String> {
+    self.headers.get("Origin")
+  }
+
+  /// The cookies sent in the Cookie header, in the order they were sent.
+  pub fn cookies(&self) -> Vec<(String, String)> {
+    self.headers.get_all("Cookie")
+      .flat_map(|value| value.split(';'))
+      .filter_map(|cookie| cookie.trim_matches(is_whitespace).split_once('='))
+      .map(|(name, value)| (name.trim_matches(is_whitespace).to_owned(), value.trim_matches(is_whitespace).trim_matches('"').to_owned()))
+      .collect()
+  }
+
+  /// The value of the first cookie with this name.
+  pub fn cookie(&self, name: &str) -> Option<String> {
+    self.cookies().into_iter().find(|(cookie_name, _)| cookie_name == name).map(|(_, value)| value)
+  }
+
+  /// Serializes the request line and headers, with CRLF line endings.
+  /// Like in responses, headers that would break the framing are left out.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut request = format!("{} {} {}\r\n", self.method, self.path, self.http_version);
+    for (name, value) in self.headers.iter().filter(|(name, value)| is_token(name) && is_field_value(value)) {
+      request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    request.push_str("\r\n");
+    request.into_bytes()
+  }
 }
 
-#[derive(PartialEq, Debug)]
+fn parse_headers<'a>(lines: impl Iterator<Item = &'a str>) -> Result<Headers, Error> {
+  let mut headers = Headers::new();
+  for line in lines {
+    if line.len() > MAX_HEADER_LINE_LENGTH {
+      return Err(Error::Capacity("a header line is longer than 1024 bytes"));
+    }
+    if headers.len() == MAX_HEADER_COUNT {
+      return Err(Error::Capacity("the message has more than 64 headers"));
+    }
+
+    let (name, value) = parse_header_line(line)?;
+    headers.insert(name, value);
+  }
+
+  Ok(headers)
+}
+
+fn parse_header_line(line: &str) -> Result<(&str, &str), Error> {
+  let malformed = || Error::Handshake(format!("malformed header line: {:?}", line));
+
+  let colon_index = line.find(':').ok_or_else(malformed)?;
+  let name = &line[..colon_index];
+
+  // Whitespace isn't allowed in the name or between the name and the colon, and a line
+  // starting with whitespace would be the obsolete line folding, which we don't support.
+  if name.is_empty() || name.contains(is_whitespace) {
+    return Err(malformed());
+  }
+
+  Ok((name, line[colon_index + 1..].trim_matches(is_whitespace)))
+}
+
+/// The reason phrase for the status codes a server refuses upgrades with.
+pub(crate) fn reason_phrase(status_code: u16) -> &'static str {
+  match status_code {
+    400 => "Bad Request",
+    401 => "Unauthorized",
+    403 => "Forbidden",
+    404 => "Not Found",
+    409 => "Conflict",
+    426 => "Upgrade Required",
+    429 => "Too Many Requests",
+    431 => "Request Header Fields Too Large",
+    500 => "Internal Server Error",
+    503 => "Service Unavailable",
+    _ => "Error",
+  }
+}
+
+/// The path of a request target, without the query string.
+pub(crate) fn path_without_query(target: &str) -> &str {
+  target.split('?').next().unwrap_or_default()
+}
+
+/// Parses the query string of a request target, like "/chat?room=1&name=Jo%20Lee".
+/// Parameters without a value get an empty one.
+pub(crate) fn parse_query(target: &str) -> Vec<(String, String)> {
+  let query = match target.split_once('?') {
+    Some((_, query)) => query,
+    None => return Vec::new(),
+  };
+
+  query.split('&').filter(|param| !param.is_empty()).map(|param| {
+    let (name, value) = param.split_once('=').unwrap_or((param, ""));
+    (percent_decode(&name.replace('+', " ")), percent_decode(&value.replace('+', " ")))
+  }).collect()
+}
+
+/// Decodes %XX escapes. Malformed escapes are kept as they are, and invalid UTF-8 is replaced.
+pub(crate) fn percent_decode(text: &str) -> String {
+  let bytes = text.as_bytes();
+  let mut decoded = Vec::with_capacity(bytes.len());
+
+  let mut index = 0;
+  while index < bytes.len() {
+    let escaped = match bytes.get(index + 1..index + 3) {
+      Some(hex) if bytes[index] == b'%' && hex.iter().all(u8::is_ascii_hexdigit) => {
+        std::str::from_utf8(hex).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok())
+      }
+      _ => None,
+    };
+
+    match escaped {
+      Some(byte) => {
+        decoded.push(byte);
+        index += 3;
+      }
+      None => {
+        decoded.push(bytes[index]);
+        index += 1;
+      }
+    }
+  }
+
+  String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Optional whitespace in HTTP is spaces and horizontal tabs.
+fn is_whitespace(c: char) -> bool {
+  c == ' ' || c == '\t'
+}
+
+/// Whether the text is a token, which header names have to be.
+pub(crate) fn is_token(text: &str) -> bool {
+  !text.is_empty() && text.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+/// Whether the text can be sent as a header value or reason phrase without ending the line early.
+pub(crate) fn is_field_value(text: &str) -> bool {
+  !text.contains(['\r', '\n', '\0'])
+}
+
+/// The response to an upgrade request. Either `101 Switching Protocols`, which completes
+/// the handshake, or an error response that refuses it.
+#[derive(PartialEq, Debug, Clone)]
 pub struct HttpUpgradeResponse {
-  pub sec_websocket_accept: String,
+  pub status_code: u16,
+  pub reason_phrase: String,
+  pub sec_websocket_accept: Option<String>,
+  // The subprotocol we picked from the ones the client offered.
+  pub subprotocol: Option<String>,
+  // The negotiated extensions with their parameters, like "permessage-deflate; client_max_window_bits=15".
+  pub extensions: Vec<String>,
+  // Headers sent after the ones above, like Set-Cookie. A parsed response has every header here.
+  pub headers: Headers,
+  pub body: String,
+}
+
+impl HttpUpgradeResponse {
+  pub fn switching_protocols(sec_websocket_accept: &str) -> HttpUpgradeResponse {
+    HttpUpgradeResponse {
+      status_code: 101,
+      reason_phrase: "Switching Protocols".to_owned(),
+      sec_websocket_accept: Some(sec_websocket_accept.to_owned()),
+      subprotocol: None,
+      extensions: Vec::new(),
+      headers: Headers::new(),
+      body: String::new(),
+    }
+  }
+
+  /// A response that refuses the upgrade. The connection is closed after it has been sent.
+  pub fn error(status_code: u16, reason_phrase: &str) -> HttpUpgradeResponse {
+    HttpUpgradeResponse {
+      status_code,
+      reason_phrase: reason_phrase.to_owned(),
+      sec_websocket_accept: None,
+      subprotocol: None,
+      extensions: Vec::new(),
+      headers: Headers::new(),
+      body: String::new(),
+    }
+  }
+
+  pub fn with_subprotocol(mut self, subprotocol: &str) -> HttpUpgradeResponse {
+    self.subprotocol = Some(subprotocol.to_owned());
+    self
+  }
+
+  pub fn with_extension(mut self, extension: &str) -> HttpUpgradeResponse {
+    self.extensions.push(extension.to_owned());
+    self
+  }
+
+  pub fn with_header(mut self, name: &str, value: &str) -> HttpUpgradeResponse {
+    self.headers.insert(name, value);
+    self
+  }
+
+  pub fn with_body(mut self, body: &str) -> HttpUpgradeResponse {
+    self.body = body.to_owned();
+    self
+  }
+
+  /// Parses the status line and headers of a response, without the empty line that ends them.
+  pub fn parse(message: &str) -> Result<HttpUpgradeResponse, Error> {
+    let mut lines = message.split("\r\n");
+
+    let status_line = lines.next().unwrap_or_default();
+    let mut status_line_parts = status_line.splitn(3, ' ');
+    let (status_code, reason_phrase) = match (status_line_parts.next(), status_line_parts.next(), status_line_parts.next()) {
+      (Some(http_version), Some(status_code), reason_phrase) if http_version.starts_with("HTTP/") => match status_code.parse() {
+        Ok(status_code) => (status_code, reason_phrase.unwrap_or_default()),
+        Err(_) => return Err(Error::Handshake(format!("malformed status line: {:?}", status_line))),
+      },
+      _ => return Err(Error::Handshake(format!("malformed status line: {:?}", status_line))),
+    };
+
+    let headers = parse_headers(lines)?;
+
+    let response = HttpUpgradeResponse {
+      status_code,
+      reason_phrase: reason_phrase.to_owned(),
+      sec_websocket_accept: headers.get("Sec-WebSocket-Accept"),
+      subprotocol: headers.get("Sec-WebSocket-Protocol"),
+      extensions: headers.get_list("Sec-WebSocket-Extensions").map(|extension| extension.to_owned()).collect(),
+      headers,
+      body: String::new(),
+    };
+
+    Ok(response)
+  }
+
+  pub fn is_switching_protocols(&self) -> bool {
+    self.status_code == 101
+  }
+
+  /// Serializes the status line, headers and body, with CRLF line endings.
+  ///
+  /// Nothing can break that framing: headers whose name isn't a token or whose value has a line
+  /// break are left out, and a reason phrase with a line break is replaced by the standard one.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let reason_phrase = if is_field_value(&self.reason_phrase) { &self.reason_phrase } else { reason_phrase(self.status_code) };
+    let mut response = format!("HTTP/1.1 {} {}\r\n", self.status_code, reason_phrase);
+    let mut push_header = |name: &str, value: &str| {
+      if !is_token(name) || !is_field_value(value) {
+        return;
+      }
+      response.push_str(name);
+      response.push_str(": ");
+      response.push_str(value);
+      response.push_str("\r\n");
+    };
+
+    if self.is_switching_protocols() {
+      push_header("Upgrade", "websocket");
+      push_header("Connection", "Upgrade");
+    } else {
+      push_header("Connection", "close");
+    }
+    if let Some(sec_websocket_accept) = &self.sec_websocket_accept {
+      push_header("Sec-WebSocket-Accept", sec_websocket_accept);
+    }
+    if let Some(subprotocol) = &self.subprotocol {
+      push_header("Sec-WebSocket-Protocol", subprotocol);
+    }
+    if !self.extensions.is_empty() {
+      push_header("Sec-WebSocket-Extensions", &self.extensions.join(", "));
+    }
+    for (name, value) in self.headers.iter() {
+      push_header(name, value);
+    }
+
+    // A 101 response can't have a body, because the connection switches protocols right after it
+    if !self.is_switching_protocols() {
+      if !self.body.is_empty() {
+        push_header("Content-Type", "text/plain; charset=utf-8");
+      }
+      push_header("Content-Length", &self.body.len().to_string());
+    }
+
+    response.push_str("\r\n");
+    response.push_str(&self.body);
+    response.into_bytes()
+  }
+}
+
+/// The parts of a ws:// or wss:// URL we need to connect to a server.
+#[derive(PartialEq, Debug, Clone)]
+pub(crate) struct WebSocketUrl {
+  // Whether the scheme is wss, which means the connection has to use TLS.
+  pub secure: bool,
+  // Without the brackets around IPv6 addresses.
+  pub host: String,
+  pub port: u16,
+  // The request target, like "/chat?room=1".
+  pub path: String,
+}
+
+impl WebSocketUrl {
+  pub fn parse(url: &str) -> Result<WebSocketUrl, Error> {
+    let invalid = || Error::Handshake(format!("invalid WebSocket URL: {:?}", url));
+
+    let (scheme, rest) = url.split_once("://").ok_or_else(invalid)?;
+    let secure = match scheme.to_ascii_lowercase().as_str() {
+      "ws" => false,
+      "wss" => true,
+      _ => return Err(invalid()),
+    };
+
+    // The fragment is never sent to the server
+    let rest = rest.split('#').next().unwrap_or_default();
+    let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(authority_end);
+
+    // User info isn't part of WebSocket URLs
+    if authority.contains('@') {
+      return Err(invalid());
+    }
+
+    let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+      let (host, after_host) = bracketed.split_once(']').ok_or_else(invalid)?;
+      match after_host {
+        "" => (host, None),
+        _ => (host, Some(after_host.strip_prefix(':').ok_or_else(invalid)?)),
+      }
+    } else {
+      match authority.split_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (authority, None),
+      }
+    };
+
+    if host.is_empty() {
+      return Err(invalid());
+    }
+
+    let port = match port {
+      Some(port) => port.parse().map_err(|_| invalid())?,
+      None if secure => 443,
+      None => 80,
+    };
+
+    let path = if path.starts_with('/') { path.to_owned() } else { format!("/{}", path) };
+
+    Ok(WebSocketUrl { secure, host: host.to_owned(), port, path })
+  }
+
+  /// The value of the Host header, which leaves out the port if it's the default one for the scheme.
+  pub fn host_header(&self) -> String {
+    let host = if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() };
+    let default_port = if self.secure { 443 } else { 80 };
+
+    if self.port == default_port {
+      host
+    } else {
+      format!("{}:{}", host, self.port)
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  static REQUEST: &str = "GET /chat?room=1 HTTP/1.1\r\nhost:  example.com:8000 \r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nsec-websocket-key:\tdGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13";
+
+  #[test]
+  fn it_parses_request_line_and_headers() {
+    let request = HttpUpgradeRequest::parse(REQUEST).unwrap();
+
+    assert_eq!(request.method, "GET");
+    assert_eq!(request.path, "/chat?room=1");
+    assert_eq!(request.http_version, "HTTP/1.1");
+    assert_eq!(request.host, "example.com:8000");
+    assert_eq!(request.sec_websocket_version, 13);
+    assert_eq!(request.sec_websocket_key, "dGhlIHNhbXBsZSBub25jZQ==");
+    assert_eq!(request.headers.len(), 5);
+  }
+
+  #[test]
+  fn it_parses_query_strings() {
+    assert_eq!(HttpUpgradeRequest::parse(REQUEST).unwrap().query_param("room"), Some("1"));
+    assert_eq!(parse_query("/chat?name=Jo%20Lee&greeting=hi+there&flag&&room=1&room=2"), vec![
+      ("name".to_owned(), "Jo Lee".to_owned()),
+      ("greeting".to_owned(), "hi there".to_owned()),
+      ("flag".to_owned(), "".to_owned()),
+      ("room".to_owned(), "1".to_owned()),
+      ("room".to_owned(), "2".to_owned()),
+    ]);
+    assert!(parse_query("/chat").is_empty());
+    assert_eq!(path_without_query("/chat?room=1"), "/chat");
+  }
+
+  #[test]
+  fn it_parses_origin_and_cookies() {
+    let request = HttpUpgradeRequest::parse(&format!("{}\r\nOrigin: https://example.com\r\nCookie: session=abc123; theme=\"dark\"\r\ncookie: flag; lang=en", REQUEST)).unwrap();
+
+    assert_eq!(request.origin(), Some("https://example.com".to_owned()));
+    assert_eq!(request.cookies(), vec![
+      ("session".to_owned(), "abc123".to_owned()),
+      ("theme".to_owned(), "dark".to_owned()),
+      ("lang".to_owned(), "en".to_owned()),
+    ]);
+    assert_eq!(request.cookie("lang"), Some("en".to_owned()));
+    assert_eq!(request.cookie("flag"), None);
+  }
+
+  #[test]
+  fn it_percent_decodes() {
+    assert_eq!(percent_decode("caf%C3%A9%2fbar"), "café/bar");
+    // Malformed escapes are kept
+    assert_eq!(percent_decode("100%"), "100%");
+    assert_eq!(percent_decode("%zz%+1"), "%zz%+1");
+  }
+
+  #[test]
+  fn it_matches_header_names_case_insensitively() {
+    let request = HttpUpgradeRequest::parse(REQUEST).unwrap();
+
+    assert_eq!(request.headers.get("UPGRADE"), Some("websocket".to_owned()));
+    assert!(request.headers.contains_token("connection", "upgrade"));
+    assert!(!request.headers.contains_token("connection", "close"));
+  }
+
+  #[test]
+  fn it_combines_repeated_headers() {
+    let request = HttpUpgradeRequest::parse(&format!("{}\r\nSec-WebSocket-Protocol: chat, superchat\r\nsec-websocket-protocol: v2", REQUEST)).unwrap();
+
+    assert_eq!(request.headers.get("Sec-WebSocket-Protocol"), Some("chat, superchat, v2".to_owned()));
+    assert_eq!(request.sec_websocket_protocols, vec!["chat", "superchat", "v2"]);
+  }
+
+  #[test]
+  fn it_rejects_malformed_request_lines() {
+    for request_line in &["", "GET /", "GET / HTTP/1.1 extra", "GET  / HTTP/1.1", "GET / FTP/1.0"] {
+      assert!(matches!(HttpUpgradeRequest::parse(request_line), Err(Error::Handshake(_))), "{:?} should be rejected", request_line);
+    }
+  }
+
+  #[test]
+  fn it_rejects_malformed_header_lines() {
+    for header_line in &["Upgrade websocket", ": websocket", "Upgrade : websocket", " folded: value"] {
+      let message = format!("GET / HTTP/1.1\r\n{}", header_line);
+      assert!(matches!(HttpUpgradeRequest::parse(&message), Err(Error::Handshake(_))), "{:?} should be rejected", header_line);
+    }
+  }
+
+  #[test]
+  fn it_limits_header_count_and_length() {
+    let too_many_headers = format!("GET / HTTP/1.1{}", "\r\nX-Header: value".repeat(MAX_HEADER_COUNT + 1));
+    assert!(matches!(HttpUpgradeRequest::parse(&too_many_headers), Err(Error::Capacity(_))));
+
+    let too_long_header = format!("GET / HTTP/1.1\r\nX-Header: {}", "a".repeat(MAX_HEADER_LINE_LENGTH));
+    assert!(matches!(HttpUpgradeRequest::parse(&too_long_header), Err(Error::Capacity(_))));
+  }
+
+  #[test]
+  fn it_serializes_switching_protocols_with_crlf() {
+    let response = HttpUpgradeResponse::switching_protocols("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+      .with_subprotocol("chat")
+      .with_extension("permessage-deflate")
+      .with_extension("x-custom; level=1")
+      .with_header("Set-Cookie", "session=1");
+
+    assert_eq!(String::from_utf8(response.to_bytes()).unwrap(), "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\nSec-WebSocket-Protocol: chat\r\nSec-WebSocket-Extensions: permessage-deflate, x-custom; level=1\r\nSet-Cookie: session=1\r\n\r\n");
+  }
+
+  #[test]
+  fn it_serializes_error_responses_with_body() {
+    let response = HttpUpgradeResponse::error(426, "Upgrade Required")
+      .with_header("Sec-WebSocket-Version", "13")
+      .with_body("nope");
+
+    assert_eq!(String::from_utf8(response.to_bytes()).unwrap(), "HTTP/1.1 426 Upgrade Required\r\nConnection: close\r\nSec-WebSocket-Version: 13\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: 4\r\n\r\nnope");
+  }
+
+  #[test]
+  fn it_leaves_line_breaks_out_of_responses() {
+    let mut response = HttpUpgradeResponse::error(401, "Unauthorized\r\nX-Injected: 1")
+      .with_header("Set-Cookie", "session=1\r\nX-Injected: 1")
+      .with_header("X-Injected: 1\r\nX-Name", "1")
+      .with_header("X-Valid", "1")
+      .with_body("line\r\nbreaks");
+    response.headers.insert("", "1");
+
+    assert_eq!(String::from_utf8(response.to_bytes()).unwrap(), "HTTP/1.1 401 Unauthorized\r\nConnection: close\r\nX-Valid: 1\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: 12\r\n\r\nline\r\nbreaks");
+  }
+
+  #[test]
+  fn it_parses_extension_offers() {
+    let request = HttpUpgradeRequest::parse(&format!("{}\r\nSec-WebSocket-Extensions: permessage-deflate; client_max_window_bits, permessage-deflate ; server_max_window_bits=\"10\"", REQUEST)).unwrap();
+
+    assert_eq!(request.sec_websocket_extensions, vec![
+      ExtensionOffer { name: "permessage-deflate".to_owned(), params: vec![("client_max_window_bits".to_owned(), None)] },
+      ExtensionOffer { name: "permessage-deflate".to_owned(), params: vec![("server_max_window_bits".to_owned(), Some("10".to_owned()))] },
+    ]);
+  }
+
+  #[test]
+  fn it_serializes_requests_with_crlf() {
+    let mut request = HttpUpgradeRequest::parse(REQUEST).unwrap();
+
+    assert_eq!(String::from_utf8(request.to_bytes()).unwrap(), "GET /chat?room=1 HTTP/1.1\r\nhost: example.com:8000\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nsec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n");
+
+    request.headers = Headers::new();
+    request.headers.insert("Authorization", "Bearer 1\r\nX-Injected: 1");
+    assert_eq!(String::from_utf8(request.to_bytes()).unwrap(), "GET /chat?room=1 HTTP/1.1\r\n\r\n");
+  }
+
+  #[test]
+  fn it_parses_responses() {
+    let response = HttpUpgradeResponse::parse("HTTP/1.1 101 Switching Protocols\r\nupgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\nSec-WebSocket-Protocol: chat\r\nSec-WebSocket-Extensions: permessage-deflate, x-custom; level=1").unwrap();
+
+    assert!(response.is_switching_protocols());
+    assert_eq!(response.reason_phrase, "Switching Protocols");
+    assert_eq!(response.sec_websocket_accept, Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_owned()));
+    assert_eq!(response.subprotocol, Some("chat".to_owned()));
+    assert_eq!(response.extensions, vec!["permessage-deflate", "x-custom; level=1"]);
+    assert!(response.headers.contains_token("Upgrade", "websocket"));
+
+    let response = HttpUpgradeResponse::parse("HTTP/1.1 404 \r\nContent-Length: 0").unwrap();
+    assert_eq!((response.status_code, response.reason_phrase.as_str()), (404, ""));
+  }
+
+  #[test]
+  fn it_rejects_malformed_status_lines() {
+    for status_line in &["", "HTTP/1.1", "HTTP/1.1 abc OK", "FTP/1.0 101 Switching Protocols"] {
+      assert!(matches!(HttpUpgradeResponse::parse(status_line), Err(Error::Handshake(_))), "{:?} should be rejected", status_line);
+    }
+  }
+
+  #[test]
+  fn it_parses_websocket_urls() {
+    let url = WebSocketUrl::parse("ws://example.com:8000/chat?room=1#top").unwrap();
+    assert_eq!(url, WebSocketUrl { secure: false, host: "example.com".to_owned(), port: 8000, path: "/chat?room=1".to_owned() });
+    assert_eq!(url.host_header(), "example.com:8000");
+
+    let url = WebSocketUrl::parse("WSS://example.com?room=1").unwrap();
+    assert_eq!((url.secure, url.port, url.path.as_str()), (true, 443, "/?room=1"));
+    assert_eq!(url.host_header(), "example.com");
+
+    let url = WebSocketUrl::parse("ws://[::1]:8181").unwrap();
+    assert_eq!((url.host.as_str(), url.port, url.path.as_str()), ("::1", 8181, "/"));
+    assert_eq!(url.host_header(), "[::1]:8181");
+  }
+
+  #[test]
+  fn it_rejects_invalid_websocket_urls() {
+    for url in &["example.com", "http://example.com", "ws://", "ws://:80/", "ws://example.com:port", "ws://example.com:99999", "ws://user@example.com", "ws://[::1"] {
+      assert!(matches!(WebSocketUrl::parse(url), Err(Error::Handshake(_))), "{:?} should be rejected", url);
+    }
+  }
 }
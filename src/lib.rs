@@ -1,9 +1,40 @@
+mod close;
+mod connection_registry;
+mod deflate;
+mod error;
+mod extension;
 mod http;
+mod frame_encoder;
 mod frame_parser;
+mod handler;
+mod message;
+mod origin;
+mod router;
+mod server_handle;
 mod shake_hand;
 mod thread_pool;
+#[cfg(feature = "rustls")]
+mod tls;
 mod websocket;
 mod websocket_server;
-pub use websocket::{WebSocket,WebSocketStream};
+pub use close::{CloseCode, CloseFrame};
+pub use connection_registry::{ConnectionId,ConnectionInfo,ConnectionRegistry};
+pub use deflate::DeflateConfig;
+pub use error::Error;
+pub use extension::{Extension,ExtensionParams};
+pub use frame_encoder::{FrameEncoder, Masking};
+pub use frame_parser::{DataFrame, Opcode};
+pub use handler::WebSocketHandler;
+pub use http::{ExtensionOffer,Headers,HttpUpgradeRequest,HttpUpgradeResponse};
+pub use message::Message;
+pub use origin::OriginPolicy;
+pub use router::{IntoRouter,Router};
+pub use server_handle::ServerHandle;
+pub use shake_hand::{ConnectOptions,HandshakeDecision,SubprotocolSelector};
+pub use websocket::{Heartbeat,WebSocket,WebSocketStream};
 pub use thread_pool::ThreadPool;
-pub use websocket_server::WebSocketServer;
\ No newline at end of file
+#[cfg(feature = "rustls")]
+pub use tls::{client_config,server_config,TlsStream};
+#[cfg(feature = "rustls")]
+pub use rustls;
+pub use websocket_server::{OverflowPolicy,WebSocketServer};
\ No newline at end of file
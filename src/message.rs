@@ -0,0 +1,302 @@
+use crate::frame_parser::{DataFrame, Opcode};
+
+/// Control frames must have a payload length of 125 bytes or less.
+pub(crate) static MAX_CONTROL_FRAME_PAYLOAD_LENGTH: usize = 125; // bytes
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Message {
+    /// Turns a whole message, as handed back by the assembler, into a text or binary message.
+    pub(crate) fn from_frame(frame: DataFrame) -> Result<Message, AssemblerError> {
+        let payload = frame.payload_bytes.unwrap_or_default();
+
+        match frame.opcode {
+            Opcode::Text => String::from_utf8(payload)
+                .map(Message::Text)
+                .map_err(|error| AssemblerError::InvalidUtf8(error.utf8_error())),
+            _ => Ok(Message::Binary(payload)),
+        }
+    }
+
+    /// The whole message as a single frame, before extensions and fragmentation are applied.
+    pub(crate) fn to_frame(&self) -> DataFrame {
+        match self {
+            Message::Text(text) => DataFrame::new(true, Opcode::Text, text.as_bytes().to_vec()),
+            Message::Binary(bytes) => DataFrame::new(true, Opcode::Binary, bytes.clone()),
+        }
+    }
+}
+
+/// What the assembler hands back once a frame has been pushed to it.
+#[derive(PartialEq, Debug)]
+pub enum Received {
+    // A text or binary message, with all of its fragments joined together into a
+    // single frame. It keeps the RSV bits of the first fragment, since extensions
+    // use those to mark how the whole message was transformed.
+    Message(DataFrame),
+
+    // A close, ping or pong frame. These may arrive in the middle of a fragmented message.
+    Control(DataFrame),
+}
+
+#[derive(PartialEq, Debug)]
+pub enum AssemblerError {
+    // A continuation frame arrived without an unfinished text or binary frame before it.
+    UnexpectedContinuation,
+
+    // A new text or binary frame arrived before the previous message was finished.
+    UnfinishedMessage,
+
+    // Control frames must never be fragmented.
+    FragmentedControlFrame,
+
+    // Control frames can carry at most 125 bytes of payload.
+    ControlFrameTooLong,
+
+    // The payload of a text message was not valid UTF-8.
+    InvalidUtf8(std::str::Utf8Error),
+
+    // The fragments of the message add up to more than the maximum message size.
+    MessageTooBig,
+}
+
+/// Joins the fragments of text and binary messages together.
+///
+/// Frames are pushed in the order they are parsed. A message starts with a text
+/// or binary frame and is followed by continuation frames until one of them has
+/// `fin` set. Control frames are passed straight through.
+pub struct MessageAssembler {
+    // The first frame of the message we're assembling, if any.
+    // The payloads of the following fragments are appended to it.
+    unfinished_message: Option<DataFrame>,
+    max_message_size: Option<usize>,
+}
+
+impl Default for MessageAssembler {
+    fn default() -> Self {
+        MessageAssembler::new()
+    }
+}
+
+impl MessageAssembler {
+    pub fn new() -> MessageAssembler {
+        MessageAssembler {
+            unfinished_message: None,
+            max_message_size: None,
+        }
+    }
+
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = Some(max_message_size);
+    }
+
+    /// Returns `Ok(None)` if the frame was a fragment of a message that isn't finished yet.
+    pub fn push(&mut self, frame: DataFrame) -> Result<Option<Received>, AssemblerError> {
+        match frame.opcode {
+            Opcode::Close | Opcode::Ping | Opcode::Pong => self.push_control_frame(frame),
+            Opcode::Text | Opcode::Binary => {
+                if self.unfinished_message.is_some() {
+                    return Err(AssemblerError::UnfinishedMessage);
+                }
+
+                let mut first_frame = DataFrame::new(false, frame.opcode, Vec::new());
+                first_frame.rsv1 = frame.rsv1;
+                first_frame.rsv2 = frame.rsv2;
+                first_frame.rsv3 = frame.rsv3;
+                self.unfinished_message = Some(first_frame);
+
+                self.push_fragment(frame)
+            }
+            Opcode::Continuation => {
+                if self.unfinished_message.is_none() {
+                    return Err(AssemblerError::UnexpectedContinuation);
+                }
+
+                self.push_fragment(frame)
+            }
+        }
+    }
+
+    fn push_control_frame(&mut self, frame: DataFrame) -> Result<Option<Received>, AssemblerError> {
+        if !frame.fin {
+            return Err(AssemblerError::FragmentedControlFrame);
+        }
+
+        let payload_length = frame.payload_bytes.as_ref().map_or(0, |bytes| bytes.len());
+        if payload_length > MAX_CONTROL_FRAME_PAYLOAD_LENGTH {
+            return Err(AssemblerError::ControlFrameTooLong);
+        }
+
+        Ok(Some(Received::Control(frame)))
+    }
+
+    fn push_fragment(&mut self, frame: DataFrame) -> Result<Option<Received>, AssemblerError> {
+        let message = self
+            .unfinished_message
+            .as_mut()
+            .expect("fragments are only pushed while a message is unfinished");
+
+        if let Some(mut payload_bytes) = frame.payload_bytes {
+            let payload = message.payload_bytes.get_or_insert_with(Vec::new);
+
+            if self.max_message_size.is_some_and(|max_message_size| {
+                payload.len() + payload_bytes.len() > max_message_size
+            }) {
+                return Err(AssemblerError::MessageTooBig);
+            }
+
+            payload.append(&mut payload_bytes);
+        }
+
+        if !frame.fin {
+            return Ok(None);
+        }
+
+        let mut message = self.unfinished_message.take().unwrap();
+        message.fin = true;
+
+        Ok(Some(Received::Message(message)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(fin: bool, opcode: Opcode, payload: &[u8]) -> DataFrame {
+        DataFrame::new(fin, opcode, payload.to_vec())
+    }
+
+    fn message(received: Result<Option<Received>, AssemblerError>) -> Message {
+        match received {
+            Ok(Some(Received::Message(frame))) => Message::from_frame(frame).unwrap(),
+            other => panic!("expected a message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_delivers_unfragmented_messages() {
+        let mut assembler = MessageAssembler::new();
+
+        assert_eq!(
+            message(assembler.push(frame(true, Opcode::Text, b"Hello"))),
+            Message::Text("Hello".to_owned())
+        );
+        assert_eq!(
+            message(assembler.push(frame(true, Opcode::Binary, &[1, 2, 3]))),
+            Message::Binary(vec![1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn it_supports_payload_split_in_multiple_frames() {
+        let mut assembler = MessageAssembler::new();
+
+        assert_eq!(assembler.push(frame(false, Opcode::Text, b"Hel")), Ok(None));
+        assert_eq!(
+            assembler.push(frame(false, Opcode::Continuation, b"lo ")),
+            Ok(None)
+        );
+        assert_eq!(
+            assembler.push(frame(true, Opcode::Continuation, b"world")),
+            Ok(Some(Received::Message(frame(
+                true,
+                Opcode::Text,
+                b"Hello world"
+            ))))
+        );
+    }
+
+    #[test]
+    fn it_keeps_the_reserved_bits_of_the_first_frame() {
+        let mut assembler = MessageAssembler::new();
+        let mut first = frame(false, Opcode::Binary, &[1]);
+        first.rsv1 = true;
+
+        assert_eq!(assembler.push(first), Ok(None));
+
+        let mut expected = frame(true, Opcode::Binary, &[1, 2]);
+        expected.rsv1 = true;
+        assert_eq!(
+            assembler.push(frame(true, Opcode::Continuation, &[2])),
+            Ok(Some(Received::Message(expected)))
+        );
+    }
+
+    #[test]
+    fn it_passes_control_frames_through_in_the_middle_of_a_message() {
+        let mut assembler = MessageAssembler::new();
+
+        assert_eq!(assembler.push(frame(false, Opcode::Binary, &[1])), Ok(None));
+        assert_eq!(
+            assembler.push(frame(true, Opcode::Ping, b"ping")),
+            Ok(Some(Received::Control(frame(true, Opcode::Ping, b"ping"))))
+        );
+        assert_eq!(
+            message(assembler.push(frame(true, Opcode::Continuation, &[2]))),
+            Message::Binary(vec![1, 2])
+        );
+    }
+
+    #[test]
+    fn it_rejects_continuation_without_unfinished_message() {
+        let mut assembler = MessageAssembler::new();
+
+        assert_eq!(
+            assembler.push(frame(true, Opcode::Continuation, b"oops")),
+            Err(AssemblerError::UnexpectedContinuation)
+        );
+    }
+
+    #[test]
+    fn it_rejects_new_message_before_previous_is_finished() {
+        let mut assembler = MessageAssembler::new();
+
+        assert_eq!(assembler.push(frame(false, Opcode::Text, b"Hel")), Ok(None));
+        assert_eq!(
+            assembler.push(frame(true, Opcode::Text, b"lo")),
+            Err(AssemblerError::UnfinishedMessage)
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_control_frames() {
+        let mut assembler = MessageAssembler::new();
+
+        assert_eq!(
+            assembler.push(frame(false, Opcode::Ping, b"")),
+            Err(AssemblerError::FragmentedControlFrame)
+        );
+        assert_eq!(
+            assembler.push(frame(true, Opcode::Pong, &[0; 126])),
+            Err(AssemblerError::ControlFrameTooLong)
+        );
+    }
+
+    #[test]
+    fn it_rejects_text_messages_with_invalid_utf8() {
+        assert!(matches!(
+            Message::from_frame(frame(true, Opcode::Text, &[0xC3, 0x28])),
+            Err(AssemblerError::InvalidUtf8(_))
+        ));
+    }
+
+    #[test]
+    fn it_rejects_messages_above_the_maximum_size() {
+        let mut assembler = MessageAssembler::new();
+        assembler.set_max_message_size(5);
+
+        assert_eq!(
+            assembler.push(frame(false, Opcode::Binary, &[0; 3])),
+            Ok(None)
+        );
+        assert_eq!(
+            assembler.push(frame(true, Opcode::Continuation, &[0; 3])),
+            Err(AssemblerError::MessageTooBig)
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use crate::http::HttpUpgradeRequest;
+
+/// Which web pages may open connections, judged by the Origin header browsers send with
+/// every upgrade request. This protects browser clients from cross-site WebSocket hijacking.
+/// Requests from other origins are answered with 403 Forbidden.
+///
+/// Requests without an Origin header don't come from a browser, so every policy allows them.
+/// Origins are compared case-insensitively, and "https://example.com:443" is the same
+/// origin as "https://example.com".
+#[derive(PartialEq, Debug, Clone)]
+pub enum OriginPolicy {
+    Any,
+
+    // Only these origins, like "https://example.com" or "http://localhost:3000".
+    Exact(Vec<String>),
+
+    // Origins matching one of these patterns, where "*." stands for any subdomain, like
+    // "https://*.example.com". The pattern doesn't match "https://example.com" itself.
+    Subdomains(Vec<String>),
+
+    // Only pages served from the host the client connects to, according to the Host header.
+    SameAsHost,
+}
+
+impl OriginPolicy {
+    pub fn exact(origins: &[&str]) -> OriginPolicy {
+        OriginPolicy::Exact(origins.iter().map(|origin| origin.to_string()).collect())
+    }
+
+    pub fn subdomains(patterns: &[&str]) -> OriginPolicy {
+        OriginPolicy::Subdomains(patterns.iter().map(|pattern| pattern.to_string()).collect())
+    }
+
+    pub fn allows(&self, request: &HttpUpgradeRequest) -> bool {
+        let origin = match request.origin() {
+            Some(origin) => origin,
+            None => return true,
+        };
+
+        match self {
+            OriginPolicy::Any => true,
+            OriginPolicy::Exact(origins) => {
+                origins.iter().any(|allowed| same_origin(allowed, &origin))
+            }
+            OriginPolicy::Subdomains(patterns) => patterns
+                .iter()
+                .any(|pattern| matches_subdomain(pattern, &origin)),
+            OriginPolicy::SameAsHost => same_as_host(&origin, &request.host),
+        }
+    }
+}
+
+/// An origin split into its parts, with the host lowercased and the port filled in.
+#[derive(PartialEq, Debug)]
+struct Origin {
+    scheme: String,
+    host: String,
+    port: Option<u16>,
+}
+
+impl Origin {
+    // "null" and other opaque origins don't parse, so they only match exactly.
+    fn parse(origin: &str) -> Option<Origin> {
+        let (scheme, authority) = origin.split_once("://")?;
+        let authority = authority.trim_end_matches('/');
+        let scheme = scheme.to_ascii_lowercase();
+        let (host, port) = split_host_port(authority)?;
+
+        Some(Origin {
+            port: port.or_else(|| default_port(&scheme)),
+            scheme,
+            host,
+        })
+    }
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" | "ws" => Some(80),
+        "https" | "wss" => Some(443),
+        _ => None,
+    }
+}
+
+/// Splits "host:port", where the host may be a bracketed IPv6 address.
+/// Returns `None` if the host isn't valid or the port isn't a number.
+fn split_host_port(authority: &str) -> Option<(String, Option<u16>)> {
+    let (host, port) = match authority.rfind(':') {
+        Some(index) if !authority[index..].contains(']') => (
+            &authority[..index],
+            Some(authority[index + 1..].parse().ok()?),
+        ),
+        _ => (authority, None),
+    };
+
+    if is_valid_host(host) {
+        Some((host.to_ascii_lowercase(), port))
+    } else {
+        None
+    }
+}
+
+/// Whether the host is a domain name, an IPv4 address or a bracketed IPv6 address. Anything else,
+/// like the "#" in "https://evil.example#.example.com", is a path, fragment or user name.
+fn is_valid_host(host: &str) -> bool {
+    match host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+    {
+        Some(address) => {
+            !address.is_empty()
+                && address
+                    .chars()
+                    .all(|c| c.is_ascii_hexdigit() || c == ':' || c == '.')
+        }
+        None => {
+            !host.is_empty()
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+        }
+    }
+}
+
+// Opaque origins like "null" are compared as they are. Malformed ones match nothing.
+fn same_origin(a: &str, b: &str) -> bool {
+    match (Origin::parse(a), Origin::parse(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => !a.contains("://") && a.eq_ignore_ascii_case(b),
+    }
+}
+
+fn matches_subdomain(pattern: &str, origin: &str) -> bool {
+    let origin = match Origin::parse(origin) {
+        Some(origin) => origin,
+        None => return false,
+    };
+
+    // The wildcard isn't a valid host, so it's taken off before the pattern is parsed
+    match pattern.split_once("://*.") {
+        Some((scheme, domain)) => match Origin::parse(&format!("{}://{}", scheme, domain)) {
+            Some(pattern) => {
+                pattern.scheme == origin.scheme
+                    && pattern.port == origin.port
+                    && origin.host.ends_with(&format!(".{}", pattern.host))
+            }
+            None => false,
+        },
+        None => Origin::parse(pattern).is_some_and(|pattern| pattern == origin),
+    }
+}
+
+/// Whether the origin has the host and port of the Host header. Browsers leave out
+/// the default port in both, so a missing port means the default one of the origin's scheme.
+fn same_as_host(origin: &str, host_header: &str) -> bool {
+    let origin = match Origin::parse(origin) {
+        Some(origin) => origin,
+        None => return false,
+    };
+
+    match split_host_port(host_header) {
+        Some((host, port)) => {
+            host == origin.host && port.or_else(|| default_port(&origin.scheme)) == origin.port
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(origin: Option<&str>) -> HttpUpgradeRequest {
+        let origin_header = origin
+            .map(|origin| format!("\r\nOrigin: {}", origin))
+            .unwrap_or_default();
+        HttpUpgradeRequest::parse(&format!(
+            "GET / HTTP/1.1\r\nHost: example.com:8080{}",
+            origin_header
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn it_allows_requests_without_an_origin() {
+        assert!(OriginPolicy::exact(&[]).allows(&request(None)));
+        assert!(OriginPolicy::SameAsHost.allows(&request(None)));
+    }
+
+    #[test]
+    fn it_allows_any_origin() {
+        assert!(OriginPolicy::Any.allows(&request(Some("https://evil.example"))));
+    }
+
+    #[test]
+    fn it_allows_exact_origins() {
+        let policy = OriginPolicy::exact(&["https://example.com", "http://localhost:3000", "null"]);
+
+        assert!(policy.allows(&request(Some("https://example.com"))));
+        assert!(policy.allows(&request(Some("HTTPS://Example.com:443"))));
+        assert!(policy.allows(&request(Some("http://localhost:3000"))));
+        assert!(policy.allows(&request(Some("null"))));
+        assert!(!policy.allows(&request(Some("http://example.com"))));
+        assert!(!policy.allows(&request(Some("https://example.com.evil.example"))));
+        assert!(!policy.allows(&request(Some("https://example.com/x"))));
+        assert!(!policy.allows(&request(Some("http://localhost:3001"))));
+    }
+
+    #[test]
+    fn it_allows_subdomains() {
+        let policy = OriginPolicy::subdomains(&["https://*.example.com"]);
+
+        assert!(policy.allows(&request(Some("https://app.example.com"))));
+        assert!(policy.allows(&request(Some("https://a.b.example.com:443"))));
+        assert!(!policy.allows(&request(Some("https://example.com"))));
+        assert!(!policy.allows(&request(Some("https://evilexample.com"))));
+        assert!(!policy.allows(&request(Some("http://app.example.com"))));
+        assert!(!policy.allows(&request(Some("https://app.example.com:8443"))));
+        assert!(!policy.allows(&request(Some("https://evil.com#.example.com"))));
+        assert!(!policy.allows(&request(Some("https://evil.com/.example.com"))));
+        assert!(!policy.allows(&request(Some("https://evil.com?.example.com"))));
+        assert!(!policy.allows(&request(Some("https://user@app.example.com"))));
+        assert!(!policy.allows(&request(Some("https://evil.com:443#.example.com"))));
+    }
+
+    #[test]
+    fn it_allows_the_origin_of_the_host() {
+        let policy = OriginPolicy::SameAsHost;
+
+        assert!(policy.allows(&request(Some("http://example.com:8080"))));
+        assert!(!policy.allows(&request(Some("http://example.com"))));
+        assert!(!policy.allows(&request(Some("http://evil.example:8080"))));
+        assert!(!policy.allows(&request(Some("null"))));
+
+        assert!(same_as_host("https://example.com", "example.com"));
+        assert!(same_as_host("http://[::1]:3000", "[::1]:3000"));
+        assert!(!same_as_host("http://example.com/x", "example.com/x"));
+    }
+}
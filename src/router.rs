@@ -0,0 +1,254 @@
+use crate::handler::WebSocketHandler;
+use crate::http::{path_without_query, percent_decode};
+use std::sync::Arc;
+
+pub(crate) type HandlerFactory = dyn Fn() -> Box<dyn WebSocketHandler> + Send + Sync;
+
+// The parameters a route captured, by name, in the order they appear in the pattern.
+type PathParams = Vec<(String, String)>;
+
+/// The name the rest of the path matched by a trailing `*` is captured under.
+static REST_PARAM: &str = "*";
+
+/// Picks the handler for a connection by the path of its upgrade request.
+/// Requests to paths that no route matches are answered with 404 Not Found.
+///
+/// Patterns are made of segments separated by "/":
+/// - "/chat" matches exactly that path, with or without a trailing slash.
+/// - ":name" matches any single segment and captures it, like "/rooms/:room".
+/// - A trailing "*" matches the rest of the path, including nothing, like "/metrics/*".
+///   The rest is captured under "*" as it was sent: unlike ":name" parameters, it isn't
+///   percent-decoded, since a decoded "%2F" couldn't be told apart from a "/".
+///
+/// The query string is ignored when matching. Routes are tried in the order they were added.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+struct Route {
+    segments: Vec<Segment>,
+    handler_factory: Arc<HandlerFactory>,
+}
+
+#[derive(PartialEq, Debug)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Rest,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router { routes: Vec::new() }
+    }
+
+    /// Serve requests to paths matching `pattern` with handlers from `handler_factory`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if "*" is used anywhere but as the last segment.
+    pub fn add_route<F, H>(&mut self, pattern: &str, handler_factory: F)
+    where
+        F: Fn() -> H + Send + Sync + 'static,
+        H: WebSocketHandler + 'static,
+    {
+        self.routes.push(Route {
+            segments: parse_pattern(pattern),
+            handler_factory: Arc::new(move || Box::new(handler_factory())),
+        });
+    }
+
+    /// Finds the first route matching the path of the request target,
+    /// along with the parameters it captured.
+    pub(crate) fn route(&self, target: &str) -> Option<(Arc<HandlerFactory>, PathParams)> {
+        let path = path_without_query(target);
+        let path_segments: Vec<(usize, &str)> = segments(path).collect();
+
+        self.routes.iter().find_map(|route| {
+            let params = route.matches(path, &path_segments)?;
+            Some((Arc::clone(&route.handler_factory), params))
+        })
+    }
+}
+
+impl Route {
+    fn matches(&self, path: &str, path_segments: &[(usize, &str)]) -> Option<PathParams> {
+        let mut params = Vec::new();
+
+        for (index, segment) in self.segments.iter().enumerate() {
+            let path_segment = path_segments
+                .get(index)
+                .map(|(_, path_segment)| *path_segment);
+            match segment {
+                Segment::Rest => {
+                    let rest = path_segments
+                        .get(index)
+                        .map_or("", |(start, _)| &path[*start..]);
+                    params.push((REST_PARAM.to_owned(), rest.to_owned()));
+                    return Some(params);
+                }
+                Segment::Literal(literal) if path_segment == Some(literal.as_str()) => {}
+                Segment::Param(name) => params.push((name.clone(), percent_decode(path_segment?))),
+                Segment::Literal(_) => return None,
+            }
+        }
+
+        if path_segments.len() == self.segments.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+}
+
+/// Something a `WebSocketServer` can take as its handlers: a `Router`, or a
+/// handler factory, which then serves every path.
+pub trait IntoRouter {
+    fn into_router(self) -> Router;
+}
+
+impl IntoRouter for Router {
+    fn into_router(self) -> Router {
+        self
+    }
+}
+
+impl<F, H> IntoRouter for F
+where
+    F: Fn() -> H + Send + Sync + 'static,
+    H: WebSocketHandler + 'static,
+{
+    fn into_router(self) -> Router {
+        let mut router = Router::new();
+        router.add_route("/*", self);
+        router
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    let segments: Vec<Segment> = segments(pattern)
+        .map(|(_, segment)| {
+            if segment == "*" {
+                Segment::Rest
+            } else if let Some(name) = segment.strip_prefix(':') {
+                Segment::Param(name.to_owned())
+            } else {
+                Segment::Literal(segment.to_owned())
+            }
+        })
+        .collect();
+
+    let rest_position = segments
+        .iter()
+        .position(|segment| *segment == Segment::Rest);
+    assert!(
+        rest_position.is_none_or(|position| position == segments.len() - 1),
+        "\"*\" may only end a route pattern: {:?}",
+        pattern
+    );
+
+    segments
+}
+
+// The segments of the path, each with the index it starts at. Empty segments
+// are skipped, so "/chat/" and "/chat" are the same path.
+fn segments(path: &str) -> impl Iterator<Item = (usize, &str)> {
+    path.split('/')
+        .scan(0, |start, segment| {
+            let segment_start = *start;
+            *start += segment.len() + 1;
+            Some((segment_start, segment))
+        })
+        .filter(|(_, segment)| !segment.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Handler;
+    impl WebSocketHandler for Handler {}
+
+    fn router(patterns: &[&str]) -> Router {
+        let mut router = Router::new();
+        for pattern in patterns {
+            router.add_route(pattern, || Handler);
+        }
+        router
+    }
+
+    fn params(router: &Router, target: &str) -> Option<PathParams> {
+        router.route(target).map(|(_, params)| params)
+    }
+
+    fn param(name: &str, value: &str) -> (String, String) {
+        (name.to_owned(), value.to_owned())
+    }
+
+    #[test]
+    fn it_matches_exact_paths() {
+        let router = router(&["/chat"]);
+
+        assert_eq!(params(&router, "/chat"), Some(vec![]));
+        assert_eq!(params(&router, "/chat/?room=1"), Some(vec![]));
+        assert_eq!(params(&router, "/"), None);
+        assert_eq!(params(&router, "/chat/more"), None);
+        assert_eq!(params(&router, "/chatter"), None);
+    }
+
+    #[test]
+    fn it_captures_path_params() {
+        let router = router(&["/rooms/:room/users/:user"]);
+
+        assert_eq!(
+            params(&router, "/rooms/news/users/jo%20lee?x=1"),
+            Some(vec![param("room", "news"), param("user", "jo lee")])
+        );
+        assert_eq!(params(&router, "/rooms/news/users"), None);
+    }
+
+    #[test]
+    fn it_matches_prefixes() {
+        let router = router(&["/metrics/*"]);
+
+        assert_eq!(params(&router, "/metrics"), Some(vec![param("*", "")]));
+        assert_eq!(
+            params(&router, "/metrics/cpu/1"),
+            Some(vec![param("*", "cpu/1")])
+        );
+        assert_eq!(params(&router, "/metric"), None);
+    }
+
+    #[test]
+    fn it_captures_the_rest_as_it_was_sent() {
+        let router = router(&["/files/*"]);
+
+        assert_eq!(
+            params(&router, "/files/a%20b//c%2Fd/?x=1"),
+            Some(vec![param("*", "a%20b//c%2Fd/")])
+        );
+        assert_eq!(params(&router, "//files//a"), Some(vec![param("*", "a")]));
+    }
+
+    #[test]
+    fn it_tries_routes_in_order() {
+        let router = router(&["/rooms/lobby", "/rooms/:room", "/*"]);
+
+        assert_eq!(params(&router, "/rooms/lobby"), Some(vec![]));
+        assert_eq!(
+            params(&router, "/rooms/news"),
+            Some(vec![param("room", "news")])
+        );
+        assert_eq!(
+            params(&router, "/anything/else"),
+            Some(vec![param("*", "anything/else")])
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn it_panics_on_rest_in_the_middle() {
+        router(&["/metrics/*/cpu"]);
+    }
+}
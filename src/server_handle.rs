@@ -0,0 +1,398 @@
+use crate::close::{CloseCode, CloseFrame};
+use crate::connection_registry::{ConnectionId, ConnectionInfo, ConnectionRegistry};
+use crate::thread_pool::ThreadPool;
+use crate::websocket::Command;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often a shutting down server checks whether every connection has closed.
+static SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How many commands may wait for a connection to pick them up. Connections pick them up
+/// between reads, which wait up to 100 milliseconds, so this has to cover bursts of broadcasts.
+pub(crate) static COMMAND_QUEUE_SIZE: usize = 1024;
+
+/// What the accept loop, the connections and the handle of a running server share.
+#[derive(Default)]
+pub(crate) struct ServerState {
+    shutting_down: AtomicBool,
+    next_connection_id: AtomicU64,
+    connections: Mutex<BTreeMap<ConnectionId, LiveConnection>>,
+
+    // Connections that are being served, or waiting for a worker or a serving slot.
+    admitted_connections: AtomicUsize,
+
+    // Connections that hold a serving slot, and a signal for when one frees up.
+    serving_connections: Mutex<usize>,
+    serving_slot_freed: Condvar,
+
+    // Connections turned away because the server was at its connection limit.
+    refused_connections: AtomicU64,
+}
+
+struct LiveConnection {
+    commands: SyncSender<Command>,
+
+    // A clone of the socket, so the connection can be cut off if it doesn't close in time.
+    socket: TcpStream,
+    remote_addr: SocketAddr,
+
+    // The requested path, once the opening handshake has completed.
+    path: Option<String>,
+    // Membership ends with the connection, since it's dropped along with it.
+    rooms: BTreeSet<String>,
+}
+
+impl LiveConnection {
+    fn is_open(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Queues the command, unless the connection has ended. A connection whose queue is full
+    /// has fallen too far behind, usually because its client stopped reading, and is cut off.
+    /// Returns whether the command was queued.
+    fn queue(&self, command: Command) -> bool {
+        match self.commands.try_send(command) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                // The connection is most likely stuck writing to the client, so a close frame
+                // wouldn't get through either. Cutting it off makes that write fail.
+                let _ = self.socket.shutdown(Shutdown::Both);
+                false
+            }
+            // The connection has ended but not unregistered yet
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+    fn info(&self, id: ConnectionId) -> Option<ConnectionInfo> {
+        let path = self.path.clone()?;
+
+        Some(ConnectionInfo {
+            id,
+            remote_addr: self.remote_addr,
+            path,
+            rooms: self.rooms.iter().cloned().collect(),
+        })
+    }
+}
+
+impl ServerState {
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Keeps track of the connection until the returned registration is dropped.
+    ///
+    /// Connections must check `is_shutting_down` after registering, since a connection
+    /// registered after the shutdown started doesn't get a close command.
+    pub fn register(
+        self: &Arc<Self>,
+        socket: TcpStream,
+        remote_addr: SocketAddr,
+        commands: SyncSender<Command>,
+    ) -> Registration {
+        let id = ConnectionId(self.next_connection_id.fetch_add(1, Ordering::SeqCst));
+        self.connections.lock().unwrap().insert(
+            id,
+            LiveConnection {
+                commands,
+                socket,
+                remote_addr,
+                path: None,
+                rooms: BTreeSet::new(),
+            },
+        );
+
+        Registration {
+            state: Arc::clone(self),
+            id,
+        }
+    }
+
+    /// Counts the connection as admitted until the returned admission is dropped.
+    pub fn admit(self: &Arc<Self>) -> Admission {
+        self.admitted_connections.fetch_add(1, Ordering::SeqCst);
+        Admission {
+            state: Arc::clone(self),
+        }
+    }
+
+    /// Waits until fewer than `max_connections` connections are being served, and counts this
+    /// one as served until the returned slot is dropped. Returns `None` if the server shuts
+    /// down first.
+    pub fn occupy_serving_slot(self: &Arc<Self>, max_connections: usize) -> Option<ServingSlot> {
+        let mut serving = self.serving_connections.lock().unwrap();
+        while *serving >= max_connections {
+            if self.is_shutting_down() {
+                return None;
+            }
+            // Shutdowns don't signal, so the flag is checked again every so often
+            serving = self
+                .serving_slot_freed
+                .wait_timeout(serving, SHUTDOWN_POLL_INTERVAL)
+                .unwrap()
+                .0;
+        }
+        *serving += 1;
+
+        Some(ServingSlot {
+            state: Arc::clone(self),
+        })
+    }
+
+    pub fn admitted_count(&self) -> usize {
+        self.admitted_connections.load(Ordering::SeqCst)
+    }
+
+    pub fn record_refusal(&self) {
+        self.refused_connections.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Queues the command on every open connection but `except`, or only on the members
+    /// of `room` if one is given. Returns how many connections it was queued on.
+    pub fn send_to_all(
+        &self,
+        command: &Command,
+        room: Option<&str>,
+        except: Option<ConnectionId>,
+    ) -> usize {
+        let connections = self.connections.lock().unwrap();
+        let recipients = connections.iter().filter(|(id, connection)| {
+            connection.is_open()
+                && Some(**id) != except
+                && room.is_none_or(|room| connection.rooms.contains(room))
+        });
+
+        recipients
+            .filter(|(_, connection)| connection.queue(command.clone()))
+            .count()
+    }
+
+    /// Queues the command on an open connection. Returns false if there is no such connection,
+    /// or if it was cut off because it fell too far behind.
+    pub fn send_to(&self, id: ConnectionId, command: Command) -> bool {
+        match self.connections.lock().unwrap().get(&id) {
+            Some(connection) if connection.is_open() => connection.queue(command),
+            _ => false,
+        }
+    }
+
+    /// Adds an open connection to the room. Returns false if there is no such connection.
+    pub fn join(&self, id: ConnectionId, room: &str) -> bool {
+        match self.connections.lock().unwrap().get_mut(&id) {
+            Some(connection) if connection.is_open() => {
+                connection.rooms.insert(room.to_owned());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Removes the connection from the room. Returns false if it wasn't a member.
+    pub fn leave(&self, id: ConnectionId, room: &str) -> bool {
+        self.connections
+            .lock()
+            .unwrap()
+            .get_mut(&id)
+            .is_some_and(|connection| connection.rooms.remove(room))
+    }
+
+    pub fn room_members(&self, room: &str) -> Vec<ConnectionId> {
+        self.connections
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, connection)| connection.rooms.contains(room))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Every room that has members, with how many it has.
+    pub fn room_sizes(&self) -> BTreeMap<String, usize> {
+        let mut sizes = BTreeMap::new();
+        for connection in self.connections.lock().unwrap().values() {
+            for room in &connection.rooms {
+                *sizes.entry(room.clone()).or_insert(0) += 1;
+            }
+        }
+
+        sizes
+    }
+
+    pub fn connection_info(&self, id: ConnectionId) -> Option<ConnectionInfo> {
+        self.connections
+            .lock()
+            .unwrap()
+            .get(&id)
+            .and_then(|connection| connection.info(id))
+    }
+
+    pub fn connection_infos(&self) -> Vec<ConnectionInfo> {
+        self.connections
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(id, connection)| connection.info(*id))
+            .collect()
+    }
+
+    fn registered_count(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    fn close_all(&self, close_frame: CloseFrame) {
+        for connection in self.connections.lock().unwrap().values() {
+            // The connection may have ended without unregistering yet, which is fine
+            connection.queue(Command::Close(close_frame.clone()));
+        }
+    }
+
+    fn cut_off_all(&self) {
+        for connection in self.connections.lock().unwrap().values() {
+            if let Err(error) = connection.socket.shutdown(Shutdown::Both) {
+                log::debug!("Failed to cut off connection: {}", error);
+            }
+        }
+    }
+}
+
+/// Removes a connection from the server's live connections when dropped.
+pub(crate) struct Registration {
+    state: Arc<ServerState>,
+    id: ConnectionId,
+}
+
+impl Registration {
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    pub fn registry(&self) -> ConnectionRegistry {
+        ConnectionRegistry::new(Arc::clone(&self.state))
+    }
+
+    /// Makes the connection reachable through the registry, once its handshake has completed.
+    pub fn mark_open(&self, path: &str) {
+        if let Some(connection) = self.state.connections.lock().unwrap().get_mut(&self.id) {
+            connection.path = Some(path.to_owned());
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.state.connections.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Counts a connection towards the server's connection limit until dropped.
+pub(crate) struct Admission {
+    state: Arc<ServerState>,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        self.state
+            .admitted_connections
+            .fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Lets a queued connection be served in place of this one when dropped.
+pub(crate) struct ServingSlot {
+    state: Arc<ServerState>,
+}
+
+impl Drop for ServingSlot {
+    fn drop(&mut self) {
+        *self.state.serving_connections.lock().unwrap() -= 1;
+        self.state.serving_slot_freed.notify_one();
+    }
+}
+
+/// A server running in the background, returned by `WebSocketServer::start`.
+///
+/// Dropping the handle leaves the server running.
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    state: Arc<ServerState>,
+    accept_thread: JoinHandle<ThreadPool>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(
+        local_addr: SocketAddr,
+        state: Arc<ServerState>,
+        accept_thread: JoinHandle<ThreadPool>,
+    ) -> ServerHandle {
+        ServerHandle {
+            local_addr,
+            state,
+            accept_thread,
+        }
+    }
+
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The open connections, for sending them messages.
+    pub fn registry(&self) -> ConnectionRegistry {
+        ConnectionRegistry::new(Arc::clone(&self.state))
+    }
+
+    /// How many connections are being served or waiting for a worker thread.
+    pub fn connection_count(&self) -> usize {
+        self.state.admitted_count()
+    }
+
+    /// How many connections were turned away because the server was at its connection limit.
+    pub fn refused_connections(&self) -> u64 {
+        self.state.refused_connections.load(Ordering::SeqCst)
+    }
+
+    /// Blocks the calling thread for as long as the server runs.
+    pub fn wait(self) {
+        if self.accept_thread.join().is_err() {
+            log::error!("The server stopped because its accept loop panicked");
+        }
+    }
+
+    /// Stops accepting connections and closes every open connection with `CloseCode::GoingAway`.
+    /// Connections that haven't finished the closing handshake within `timeout` are cut off.
+    /// Returns once every worker thread has finished.
+    pub fn shutdown(self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        self.state.shutting_down.store(true, Ordering::SeqCst);
+
+        // The accept loop sees the flag within its poll interval and hands back the workers.
+        // If it panicked, the connections still have to be closed.
+        let pool = match self.accept_thread.join() {
+            Ok(pool) => Some(pool),
+            Err(_) => {
+                log::error!("The server's accept loop panicked");
+                None
+            }
+        };
+
+        self.state.close_all(CloseFrame {
+            code: CloseCode::GoingAway,
+            reason: "the server is shutting down".to_owned(),
+        });
+
+        while self.state.registered_count() > 0 && Instant::now() < deadline {
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+        self.state.cut_off_all();
+
+        // Dropping the pool joins the workers
+        drop(pool);
+    }
+}
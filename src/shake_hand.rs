@@ -1,35 +1,706 @@
-use crate::http::{HttpUpgradeRequest, HttpUpgradeResponse};
+use crate::error::Error;
+use crate::extension::{Extension, ExtensionChain};
+use crate::http::{
+    is_field_value, is_token, parse_query, reason_phrase, Headers, HttpUpgradeRequest,
+    HttpUpgradeResponse, WebSocketUrl,
+};
 use sha1::{Digest, Sha1};
+use std::any::Any;
+use std::sync::Arc;
 
 static HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
-pub fn shake_hand(request: &HttpUpgradeRequest) -> Result<HttpUpgradeResponse, ()> {
-    let mut owned_key = request.sec_websocket_key.to_owned();
+static SUPPORTED_WEBSOCKET_VERSION: u8 = 13;
+
+// The key is a base64 encoded random 16-byte value.
+static SEC_WEBSOCKET_KEY_LENGTH: usize = 16; // bytes
+
+/// Why the server refused an opening handshake.
+#[derive(PartialEq, Debug)]
+pub(crate) enum HandshakeError {
+    // The request isn't a valid upgrade request. Answered with 400 Bad Request.
+    BadRequest(String),
+
+    // The client wants a version of the protocol other than 13. Answered with 426 Upgrade Required.
+    UnsupportedVersion(u8),
+
+    // The request doesn't fit in our buffer. Answered with 431 Request Header Fields Too Large.
+    RequestTooLarge,
+
+    // The server is at its connection limit. Answered with 503 Service Unavailable.
+    Overloaded,
+
+    // No route matches the requested path. Answered with 404 Not Found.
+    NotFound(String),
+
+    // The origin policy doesn't allow the page that opened the connection. Answered with 403 Forbidden.
+    ForbiddenOrigin(String),
+
+    // The handshake interceptor refused the request. Answered with the response it chose.
+    Intercepted(Box<HttpUpgradeResponse>),
+
+    // The handshake interceptor added a header that can't be sent, named here.
+    // Answered with 500 Internal Server Error.
+    InvalidHeader(String),
+
+    // The handshake interceptor rejected with a status that isn't a 4xx or 5xx.
+    // Answered with 500 Internal Server Error.
+    InvalidStatus(u16),
+}
+
+impl HandshakeError {
+    /// The HTTP response to send before closing the connection.
+    pub(crate) fn to_response(&self) -> HttpUpgradeResponse {
+        let response = match self {
+            HandshakeError::BadRequest(_) => HttpUpgradeResponse::error(400, "Bad Request"),
+            HandshakeError::UnsupportedVersion(_) => {
+                HttpUpgradeResponse::error(426, "Upgrade Required")
+                    .with_header("Sec-WebSocket-Version", "13")
+            }
+            HandshakeError::RequestTooLarge => {
+                HttpUpgradeResponse::error(431, "Request Header Fields Too Large")
+            }
+            HandshakeError::Overloaded => HttpUpgradeResponse::error(503, "Service Unavailable"),
+            HandshakeError::NotFound(_) => HttpUpgradeResponse::error(404, "Not Found"),
+            HandshakeError::ForbiddenOrigin(_) => HttpUpgradeResponse::error(403, "Forbidden"),
+            HandshakeError::Intercepted(response) => return (**response).clone(),
+            HandshakeError::InvalidHeader(_) | HandshakeError::InvalidStatus(_) => {
+                HttpUpgradeResponse::error(500, "Internal Server Error")
+            }
+        };
+
+        response.with_body(&self.to_string())
+    }
+}
+
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::BadRequest(reason) => write!(f, "{}", reason),
+            HandshakeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported WebSocket version {}", version)
+            }
+            HandshakeError::RequestTooLarge => write!(f, "the request is too large"),
+            HandshakeError::Overloaded => write!(f, "the server is at its connection limit"),
+            HandshakeError::NotFound(path) => write!(f, "no route matches {}", path),
+            HandshakeError::ForbiddenOrigin(origin) => {
+                write!(f, "origin {} is not allowed", origin)
+            }
+            HandshakeError::Intercepted(response) => write!(
+                f,
+                "the handshake interceptor answered with {} {}",
+                response.status_code, response.reason_phrase
+            ),
+            HandshakeError::InvalidHeader(name) => write!(
+                f,
+                "the handshake interceptor added the invalid header {:?}",
+                name
+            ),
+            HandshakeError::InvalidStatus(status_code) => write!(
+                f,
+                "the handshake interceptor rejected with status {}, which isn't an error",
+                status_code
+            ),
+        }
+    }
+}
+
+impl From<HandshakeError> for Error {
+    fn from(error: HandshakeError) -> Self {
+        match error {
+            HandshakeError::RequestTooLarge => {
+                Error::Capacity("the handshake request is larger than 2048 bytes")
+            }
+            _ => Error::Handshake(error.to_string()),
+        }
+    }
+}
+
+type SubprotocolCallback = dyn Fn(&[String]) -> Option<String> + Send + Sync;
+
+/// How the server picks one of the subprotocols the client offers in `Sec-WebSocket-Protocol`.
+#[derive(Clone)]
+pub enum SubprotocolSelector {
+    // Pick the first of these that the client offered, so they are in order of preference.
+    Preferred(Vec<String>),
+
+    // Called with the offered subprotocols in the order the client sent them.
+    Callback(Arc<SubprotocolCallback>),
+}
+
+impl SubprotocolSelector {
+    pub fn preferred(subprotocols: &[&str]) -> SubprotocolSelector {
+        SubprotocolSelector::Preferred(subprotocols.iter().map(|s| s.to_string()).collect())
+    }
+
+    pub fn callback<F>(callback: F) -> SubprotocolSelector
+    where
+        F: Fn(&[String]) -> Option<String> + Send + Sync + 'static,
+    {
+        SubprotocolSelector::Callback(Arc::new(callback))
+    }
+
+    /// Picks a subprotocol, or none if we don't support any of the offered ones.
+    /// We never pick a subprotocol that wasn't offered, since the client would fail the connection.
+    pub fn select(&self, offered: &[String]) -> Option<String> {
+        match self {
+            SubprotocolSelector::Preferred(supported) => supported
+                .iter()
+                .find(|subprotocol| offered.contains(subprotocol))
+                .cloned(),
+            SubprotocolSelector::Callback(callback) => {
+                callback(offered).filter(|subprotocol| offered.contains(subprotocol))
+            }
+        }
+    }
+}
+
+pub(crate) type HandshakeInterceptor =
+    dyn Fn(&HttpUpgradeRequest) -> HandshakeDecision + Send + Sync;
+
+/// What a handshake interceptor decides about an upgrade request, like whether its token
+/// is valid. Create one with `accept` or `reject`.
+pub struct HandshakeDecision {
+    // The status code to refuse the upgrade with, or none to accept it.
+    rejection_status: Option<u16>,
+    // Sent along with the response, whether it accepts or rejects the upgrade.
+    headers: Headers,
+    // The body of a rejection.
+    body: String,
+    // Handed to the connection, where handlers read it with `WebSocket::user_data`.
+    user_data: Option<Box<dyn Any + Send>>,
+    // The name of the first header that couldn't be sent, which turns the decision into a 500.
+    invalid_header: Option<String>,
+}
+
+impl HandshakeDecision {
+    /// Go on with the handshake, which may still fail if the request isn't a valid upgrade request.
+    pub fn accept() -> HandshakeDecision {
+        HandshakeDecision {
+            rejection_status: None,
+            headers: Headers::new(),
+            body: String::new(),
+            user_data: None,
+            invalid_header: None,
+        }
+    }
+
+    /// Answer with `status_code`, like 401 or 403, and `body` instead of upgrading.
+    /// The status has to be a client or server error, between 400 and 599, or the
+    /// upgrade is answered with 500 Internal Server Error instead.
+    pub fn reject(status_code: u16, body: &str) -> HandshakeDecision {
+        HandshakeDecision {
+            rejection_status: Some(status_code),
+            body: body.to_owned(),
+            ..HandshakeDecision::accept()
+        }
+    }
+
+    /// Send the header with the response, like Set-Cookie when accepting
+    /// or WWW-Authenticate when rejecting.
+    ///
+    /// The name has to be a token and the value can't contain line breaks, since both often come
+    /// from the request. Otherwise the upgrade is answered with 500 Internal Server Error instead.
+    pub fn with_header(mut self, name: &str, value: &str) -> HandshakeDecision {
+        if !is_token(name) || !is_field_value(value) {
+            self.invalid_header.get_or_insert_with(|| name.to_owned());
+        } else {
+            self.headers.insert(name, value);
+        }
+        self
+    }
+
+    /// Attach data to the connection, like the user a token belongs to.
+    /// Only kept when the upgrade is accepted.
+    pub fn with_user_data<T: Any + Send>(mut self, user_data: T) -> HandshakeDecision {
+        self.user_data = Some(Box::new(user_data));
+        self
+    }
+
+    /// Splits the decision into the headers for the 101 response and the user data,
+    /// or the response that refuses the upgrade.
+    pub(crate) fn into_result(
+        self,
+    ) -> Result<(Headers, Option<Box<dyn Any + Send>>), HandshakeError> {
+        if let Some(name) = self.invalid_header {
+            return Err(HandshakeError::InvalidHeader(name));
+        }
+
+        match self.rejection_status {
+            None => Ok((self.headers, self.user_data)),
+            Some(status_code) if !(400..=599).contains(&status_code) => {
+                Err(HandshakeError::InvalidStatus(status_code))
+            }
+            Some(status_code) => {
+                let mut response =
+                    HttpUpgradeResponse::error(status_code, reason_phrase(status_code))
+                        .with_body(&self.body);
+                response.headers = self.headers;
+                Err(HandshakeError::Intercepted(Box::new(response)))
+            }
+        }
+    }
+}
+
+/// Validates the upgrade request and computes the response to it, along with the chain
+/// of extensions that were negotiated out of `extensions`.
+pub(crate) fn shake_hand(
+    request: &HttpUpgradeRequest,
+    subprotocol_selector: Option<&SubprotocolSelector>,
+    extensions: Vec<Box<dyn Extension>>,
+) -> Result<(HttpUpgradeResponse, ExtensionChain), HandshakeError> {
+    validate(request)?;
+
+    let mut response =
+        HttpUpgradeResponse::switching_protocols(&accept_key(&request.sec_websocket_key));
+
+    let subprotocol =
+        subprotocol_selector.and_then(|selector| selector.select(&request.sec_websocket_protocols));
+    if let Some(subprotocol) = subprotocol {
+        response = response.with_subprotocol(&subprotocol);
+    }
+
+    let (extension_chain, extension_header_values) =
+        ExtensionChain::negotiate(extensions, &request.sec_websocket_extensions);
+    for value in extension_header_values {
+        response = response.with_extension(&value);
+    }
+
+    Ok((response, extension_chain))
+}
+
+/// The `Sec-WebSocket-Accept` value that proves the server understood the request with this key.
+fn accept_key(sec_websocket_key: &str) -> String {
+    let mut owned_key = sec_websocket_key.to_owned();
     owned_key.push_str(HANDSHAKE_GUID);
 
     let mut hasher = Sha1::new();
     hasher.update(owned_key);
     let sha1_hash = hasher.finalize();
 
-    Ok(HttpUpgradeResponse {
-        sec_websocket_accept: base64::encode(sha1_hash),
-    })
+    base64::encode(sha1_hash)
+}
+
+fn validate(request: &HttpUpgradeRequest) -> Result<(), HandshakeError> {
+    let bad_request = |reason: &str| Err(HandshakeError::BadRequest(reason.to_owned()));
+
+    if request.method != "GET" {
+        return bad_request("the method must be GET");
+    }
+
+    if request.http_version != "HTTP/1.1" {
+        return bad_request("the HTTP version must be 1.1");
+    }
+
+    if !request.headers.contains_token("Upgrade", "websocket") {
+        return bad_request("missing Upgrade: websocket header");
+    }
+
+    if !request.headers.contains_token("Connection", "Upgrade") {
+        return bad_request("missing Connection: Upgrade header");
+    }
+
+    if request.sec_websocket_version != SUPPORTED_WEBSOCKET_VERSION {
+        return Err(HandshakeError::UnsupportedVersion(
+            request.sec_websocket_version,
+        ));
+    }
+
+    match base64::decode(&request.sec_websocket_key) {
+        Ok(key) if key.len() == SEC_WEBSOCKET_KEY_LENGTH => Ok(()),
+        _ => bad_request("Sec-WebSocket-Key must be 16 base64 encoded bytes"),
+    }
+}
+
+/// What a client sends in its upgrade request besides the headers the protocol requires.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    // The subprotocols to offer, in order of preference.
+    pub subprotocols: Vec<String>,
+
+    // Extra headers for the request, like Authorization or Origin.
+    pub headers: Headers,
+
+    // The TLS configuration for wss:// URLs. Without one, the server's
+    // certificate has to be signed by one of the Mozilla root certificates.
+    #[cfg(feature = "rustls")]
+    pub tls_config: Option<Arc<rustls::ClientConfig>>,
+}
+
+/// Builds the upgrade request a client opens the connection with, using a fresh random key.
+pub(crate) fn client_request(url: &WebSocketUrl, options: &ConnectOptions) -> HttpUpgradeRequest {
+    let sec_websocket_key = base64::encode(rand::random::<[u8; SEC_WEBSOCKET_KEY_LENGTH]>());
+
+    let mut headers = Headers::new();
+    headers.insert("Host", &url.host_header());
+    headers.insert("Upgrade", "websocket");
+    headers.insert("Connection", "Upgrade");
+    headers.insert("Sec-WebSocket-Key", &sec_websocket_key);
+    headers.insert(
+        "Sec-WebSocket-Version",
+        &SUPPORTED_WEBSOCKET_VERSION.to_string(),
+    );
+    if !options.subprotocols.is_empty() {
+        headers.insert("Sec-WebSocket-Protocol", &options.subprotocols.join(", "));
+    }
+    for (name, value) in options.headers.iter() {
+        headers.insert(name, value);
+    }
+
+    HttpUpgradeRequest {
+        method: "GET".to_owned(),
+        path: url.path.clone(),
+        query: parse_query(&url.path),
+        path_params: Vec::new(),
+        http_version: "HTTP/1.1".to_owned(),
+        host: url.host_header(),
+        sec_websocket_version: SUPPORTED_WEBSOCKET_VERSION,
+        sec_websocket_key,
+        sec_websocket_protocols: options.subprotocols.clone(),
+        sec_websocket_extensions: Vec::new(),
+        headers,
+    }
+}
+
+/// Checks that the server accepted the upgrade request, as a client must before sending any frames.
+pub(crate) fn verify_response(
+    request: &HttpUpgradeRequest,
+    response: &HttpUpgradeResponse,
+) -> Result<(), Error> {
+    let failed = |reason: String| Err(Error::Handshake(reason));
+
+    if !response.is_switching_protocols() {
+        return failed(format!(
+            "the server answered with {} {}",
+            response.status_code, response.reason_phrase
+        ));
+    }
+
+    if !response.headers.contains_token("Upgrade", "websocket") {
+        return failed("missing Upgrade: websocket header".to_owned());
+    }
+
+    if !response.headers.contains_token("Connection", "Upgrade") {
+        return failed("missing Connection: Upgrade header".to_owned());
+    }
+
+    if response.sec_websocket_accept.as_deref() != Some(&accept_key(&request.sec_websocket_key)) {
+        return failed("Sec-WebSocket-Accept doesn't match the key we sent".to_owned());
+    }
+
+    if let Some(subprotocol) = &response.subprotocol {
+        if !request.sec_websocket_protocols.contains(subprotocol) {
+            return failed(format!(
+                "the server picked the subprotocol {:?}, which we didn't offer",
+                subprotocol
+            ));
+        }
+    }
+
+    // We don't offer any extensions, so the server can't have accepted one
+    if !response.extensions.is_empty() {
+        return failed(format!(
+            "the server accepted extensions we didn't offer: {}",
+            response.extensions.join(", ")
+        ));
+    }
+
+    Ok(())
 }
 
-#[test]
-fn it_responds_to_upgrade_request() {
-    let request = HttpUpgradeRequest {
-        path: "ws://example.com:8181/",
-        host: "localhost:8181",
-        sec_websocket_version: 13,
-        sec_websocket_key: "q4xkcO32u266gldTuKaSOw==",
-    };
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::deflate::{DeflateConfig, PerMessageDeflate};
+    use std::str;
+
+    fn request(extra_headers: &str) -> HttpUpgradeRequest {
+        HttpUpgradeRequest::parse(&format!(
+            "GET /chat HTTP/1.1\r\nHost: localhost:8181\r\n{}",
+            extra_headers
+        ))
+        .unwrap()
+    }
+
+    static VALID_HEADERS: &str = "Upgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: q4xkcO32u266gldTuKaSOw==";
+
+    #[test]
+    fn it_responds_to_upgrade_request() {
+        let response = shake_hand(&request(VALID_HEADERS), None, Vec::new())
+            .unwrap()
+            .0;
+        assert_eq!(
+            response,
+            HttpUpgradeResponse::switching_protocols("fA9dggdnMPU79lJgAE3W4TRnyDM=")
+        )
+    }
+
+    #[test]
+    fn it_rejects_requests_that_are_not_upgrade_requests() {
+        let invalid_requests = [
+            VALID_HEADERS.replace("Upgrade: websocket", "Upgrade: h2c"),
+            VALID_HEADERS.replace("keep-alive, Upgrade", "keep-alive"),
+            VALID_HEADERS.replace("q4xkcO32u266gldTuKaSOw==", "c2hvcnQ="),
+            VALID_HEADERS.replace("q4xkcO32u266gldTuKaSOw==", "not base64!"),
+            VALID_HEADERS.replace("Sec-WebSocket-Key", "X-Key"),
+        ];
+
+        for headers in &invalid_requests {
+            assert!(
+                matches!(
+                    shake_hand(&request(headers), None, Vec::new()),
+                    Err(HandshakeError::BadRequest(_))
+                ),
+                "{:?} should be rejected",
+                headers
+            );
+        }
+    }
+
+    #[test]
+    fn it_rejects_other_methods() {
+        let mut request = request(VALID_HEADERS);
+        request.method = "POST".to_owned();
+
+        assert!(matches!(
+            shake_hand(&request, None, Vec::new()),
+            Err(HandshakeError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn it_asks_for_version_13() {
+        let request = request(&VALID_HEADERS.replace("Version: 13", "Version: 8"));
+
+        assert!(matches!(
+            shake_hand(&request, None, Vec::new()),
+            Err(HandshakeError::UnsupportedVersion(8))
+        ));
+        assert!(String::from_utf8(
+            HandshakeError::UnsupportedVersion(8)
+                .to_response()
+                .to_bytes()
+        )
+        .unwrap()
+        .starts_with(
+            "HTTP/1.1 426 Upgrade Required\r\nConnection: close\r\nSec-WebSocket-Version: 13\r\n"
+        ));
+    }
+
+    #[test]
+    fn it_turns_interceptor_decisions_into_responses() {
+        let rejection = HandshakeDecision::reject(401, "invalid token")
+            .with_header("WWW-Authenticate", "Bearer")
+            .into_result();
+        let response = match rejection {
+            Err(error) => error.to_response(),
+            Ok(_) => panic!("the decision was a rejection"),
+        };
+        assert_eq!(
+            (response.status_code, response.reason_phrase.as_str()),
+            (401, "Unauthorized")
+        );
+        assert_eq!(
+            response.headers.get("WWW-Authenticate"),
+            Some("Bearer".to_owned())
+        );
+        assert_eq!(response.body, "invalid token");
+
+        let (headers, user_data) = HandshakeDecision::accept()
+            .with_header("X-User", "jo")
+            .with_user_data(7_u32)
+            .into_result()
+            .ok()
+            .unwrap();
+        assert_eq!(headers.get("X-User"), Some("jo".to_owned()));
+        assert_eq!(user_data.unwrap().downcast_ref::<u32>(), Some(&7));
+    }
+
+    #[test]
+    fn it_refuses_interceptor_headers_that_would_split_the_response() {
+        let decisions = vec![
+            HandshakeDecision::accept().with_header("Set-Cookie", "id=1\r\nX-Injected: 1"),
+            HandshakeDecision::reject(401, "").with_header("WWW-Authenticate", "Bearer\n"),
+            HandshakeDecision::accept().with_header("X-Injected: 1\r\nX-User", "jo"),
+            HandshakeDecision::accept().with_header("X User", "jo"),
+        ];
 
-    let response = shake_hand(&request).unwrap();
-    assert_eq!(
-        response,
-        HttpUpgradeResponse {
-            sec_websocket_accept: "fA9dggdnMPU79lJgAE3W4TRnyDM=".to_owned()
+        for decision in decisions {
+            let response = match decision.into_result() {
+                Err(error) => error.to_response(),
+                Ok(_) => panic!("the decision has an invalid header"),
+            };
+            assert_eq!(response.status_code, 500);
+            assert!(response.headers.is_empty());
         }
-    )
+    }
+
+    #[test]
+    fn it_only_rejects_with_error_status_codes() {
+        for status_code in [101, 200, 302, 399, 600] {
+            let response = match HandshakeDecision::reject(status_code, "").into_result() {
+                Err(error) => error.to_response(),
+                Ok(_) => panic!("the decision was a rejection"),
+            };
+            assert_eq!(response.status_code, 500);
+        }
+
+        let response = match HandshakeDecision::reject(599, "").into_result() {
+            Err(error) => error.to_response(),
+            Ok(_) => panic!("the decision was a rejection"),
+        };
+        assert_eq!(response.status_code, 599);
+    }
+
+    #[test]
+    fn it_picks_the_most_preferred_offered_subprotocol() {
+        let request = request(&format!(
+            "{}\r\nSec-WebSocket-Protocol: telemetry, json-rpc",
+            VALID_HEADERS
+        ));
+        let selector = SubprotocolSelector::preferred(&["json-rpc", "telemetry"]);
+
+        let response = shake_hand(&request, Some(&selector), Vec::new()).unwrap().0;
+
+        assert_eq!(response.subprotocol, Some("json-rpc".to_owned()));
+    }
+
+    #[test]
+    fn it_picks_no_subprotocol_if_none_is_supported() {
+        let request = request(&format!(
+            "{}\r\nSec-WebSocket-Protocol: telemetry",
+            VALID_HEADERS
+        ));
+        let selector = SubprotocolSelector::preferred(&["json-rpc"]);
+
+        let response = shake_hand(&request, Some(&selector), Vec::new()).unwrap().0;
+
+        assert_eq!(response.subprotocol, None);
+    }
+
+    #[test]
+    fn it_ignores_callback_choices_that_were_not_offered() {
+        let offered = vec!["chat".to_owned(), "superchat".to_owned()];
+
+        let last = SubprotocolSelector::callback(|offered| offered.last().cloned());
+        assert_eq!(last.select(&offered), Some("superchat".to_owned()));
+
+        let made_up = SubprotocolSelector::callback(|_| Some("other".to_owned()));
+        assert_eq!(made_up.select(&offered), None);
+    }
+
+    #[test]
+    fn it_accepts_permessage_deflate_when_configured() {
+        let request = request(&format!(
+            "{}\r\nSec-WebSocket-Extensions: permessage-deflate; client_max_window_bits",
+            VALID_HEADERS
+        ));
+
+        let (response, _) = shake_hand(&request, None, Vec::new()).unwrap();
+        assert!(response.extensions.is_empty());
+
+        let deflate = PerMessageDeflate::new(DeflateConfig::default(), None);
+        let (response, extension_chain) =
+            shake_hand(&request, None, vec![Box::new(deflate)]).unwrap();
+        assert_eq!(response.extensions, vec!["permessage-deflate"]);
+        assert_eq!(extension_chain.reserved_bits(), 0b01000000);
+    }
+
+    fn client_request_with_key(
+        sec_websocket_key: &str,
+        subprotocols: &[&str],
+    ) -> HttpUpgradeRequest {
+        let url = WebSocketUrl::parse("ws://localhost:8181/chat").unwrap();
+        let options = ConnectOptions {
+            subprotocols: subprotocols.iter().map(|s| s.to_string()).collect(),
+            ..ConnectOptions::default()
+        };
+
+        let mut request = client_request(&url, &options);
+        request.sec_websocket_key = sec_websocket_key.to_owned();
+        request
+    }
+
+    #[test]
+    fn it_builds_requests_the_server_accepts() {
+        let url = WebSocketUrl::parse("ws://localhost:8181/chat").unwrap();
+        let mut options = ConnectOptions {
+            subprotocols: vec!["chat".to_owned()],
+            ..ConnectOptions::default()
+        };
+        options.headers.insert("Origin", "http://localhost");
+
+        let request = client_request(&url, &options);
+        let parsed = HttpUpgradeRequest::parse(
+            str::from_utf8(&request.to_bytes())
+                .unwrap()
+                .trim_end_matches("\r\n"),
+        )
+        .unwrap();
+
+        assert_eq!(parsed, request);
+        assert_eq!(parsed.host, "localhost:8181");
+        assert_eq!(
+            parsed.headers.get("Origin"),
+            Some("http://localhost".to_owned())
+        );
+        assert!(shake_hand(&parsed, None, Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn it_uses_a_fresh_key_for_every_request() {
+        let url = WebSocketUrl::parse("ws://localhost/").unwrap();
+        let options = ConnectOptions::default();
+
+        assert_ne!(
+            client_request(&url, &options).sec_websocket_key,
+            client_request(&url, &options).sec_websocket_key
+        );
+    }
+
+    #[test]
+    fn it_verifies_the_accept_key() {
+        let request = client_request_with_key("dGhlIHNhbXBsZSBub25jZQ==", &[]);
+
+        let response = HttpUpgradeResponse::parse("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=").unwrap();
+        assert!(verify_response(&request, &response).is_ok());
+
+        let response = HttpUpgradeResponse::parse("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: fA9dggdnMPU79lJgAE3W4TRnyDM=").unwrap();
+        assert!(matches!(
+            verify_response(&request, &response),
+            Err(Error::Handshake(_))
+        ));
+    }
+
+    #[test]
+    fn it_rejects_responses_that_do_not_complete_the_handshake() {
+        let request = client_request_with_key("dGhlIHNhbXBsZSBub25jZQ==", &["chat"]);
+        let valid = "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+
+        let invalid_responses = [
+            valid.replace("101 Switching Protocols", "200 OK"),
+            valid.replace("Upgrade: websocket", "Upgrade: h2c"),
+            valid.replace("Connection: Upgrade", "Connection: close"),
+            format!("{}\r\nSec-WebSocket-Protocol: superchat", valid),
+            format!("{}\r\nSec-WebSocket-Extensions: permessage-deflate", valid),
+        ];
+
+        for response in &invalid_responses {
+            assert!(
+                matches!(
+                    verify_response(&request, &HttpUpgradeResponse::parse(response).unwrap()),
+                    Err(Error::Handshake(_))
+                ),
+                "{:?} should be rejected",
+                response
+            );
+        }
+
+        let with_subprotocol = format!("{}\r\nSec-WebSocket-Protocol: chat", valid);
+        assert!(verify_response(
+            &request,
+            &HttpUpgradeResponse::parse(&with_subprotocol).unwrap()
+        )
+        .is_ok());
+    }
 }
@@ -75,25 +75,30 @@ impl ThreadPool {
   {
     let job = Box::new(f);
 
-    self.sender.send(Message::NewJob(job)).unwrap();
+    if self.sender.send(Message::NewJob(job)).is_err() {
+      log::error!("Dropping job, because every worker has stopped.");
+    }
   }
 }
 
 impl Drop for ThreadPool {
   fn drop(&mut self) {
-    println!("Sender terminate message to all workers.");
+    log::debug!("Sending terminate message to all workers.");
 
     for _ in &mut self.workers {
-      self.sender.send(Message::Terminate).unwrap();
+      // Workers that have stopped don't need to be told
+      let _ = self.sender.send(Message::Terminate);
     }
 
-    println!("Shutting down all workers.");
+    log::debug!("Shutting down all workers.");
 
     for worker in &mut self.workers {
-      println!("Shutting down worker {}", worker.id);
+      log::debug!("Shutting down worker {}", worker.id);
 
       if let Some(thread) = worker.thread.take() {
-        thread.join().unwrap();
+        if thread.join().is_err() {
+          log::error!("Worker {} panicked.", worker.id);
+        }
       }
     }
   }
@@ -112,12 +117,12 @@ impl Worker {
         let message = receiver.lock().unwrap().recv().unwrap();
         match message {
           Message::NewJob(job) => {
-            println!("Worker {} got a job; executing.", id);
+            log::trace!("Worker {} got a job; executing.", id);
 
             job.call_box();
           }
           Message::Terminate => {
-            println!("Worker {} was told to terminate.", id);
+            log::debug!("Worker {} was told to terminate.", id);
 
             break;
           }
@@ -0,0 +1,152 @@
+use crate::error::Error;
+use crate::websocket::WebSocketStream;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+use rustls::{ConnectionCommon, StreamOwned};
+use std::convert::TryFrom;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A TLS connection over TCP, for serving and connecting to wss:// URLs.
+///
+/// The TLS handshake is completed when the stream is created, so a bad certificate
+/// is reported right away instead of on the first read.
+pub struct TlsStream(Connection);
+
+enum Connection {
+    Server(StreamOwned<ServerConnection, TcpStream>),
+    Client(StreamOwned<ClientConnection, TcpStream>),
+}
+
+impl TlsStream {
+    /// Performs the server side of the TLS handshake on an accepted connection.
+    pub fn accept(socket: TcpStream, config: Arc<ServerConfig>) -> Result<TlsStream, Error> {
+        TlsStream::accept_before(socket, config, None)
+    }
+
+    /// Like `accept`, but gives up with `TimedOut` once the deadline, if there is one, has passed.
+    pub(crate) fn accept_before(
+        mut socket: TcpStream,
+        config: Arc<ServerConfig>,
+        deadline: Option<Instant>,
+    ) -> Result<TlsStream, Error> {
+        let mut connection = ServerConnection::new(config)?;
+        complete_handshake(&mut connection, &mut socket, deadline)?;
+
+        Ok(TlsStream(Connection::Server(StreamOwned::new(
+            connection, socket,
+        ))))
+    }
+
+    /// Performs the client side of the TLS handshake, verifying that the
+    /// server's certificate is valid for `server_name`.
+    pub fn connect(
+        mut socket: TcpStream,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+    ) -> Result<TlsStream, Error> {
+        let server_name = ServerName::try_from(server_name.to_owned()).map_err(|_| {
+            Error::Handshake(format!("{:?} isn't a valid server name", server_name))
+        })?;
+
+        let mut connection = ClientConnection::new(config, server_name)?;
+        complete_handshake(&mut connection, &mut socket, None)?;
+
+        Ok(TlsStream(Connection::Client(StreamOwned::new(
+            connection, socket,
+        ))))
+    }
+
+    fn socket(&self) -> &TcpStream {
+        match &self.0 {
+            Connection::Server(stream) => stream.get_ref(),
+            Connection::Client(stream) => stream.get_ref(),
+        }
+    }
+}
+
+fn complete_handshake<Data>(
+    connection: &mut ConnectionCommon<Data>,
+    socket: &mut TcpStream,
+    deadline: Option<Instant>,
+) -> Result<(), Error> {
+    while connection.is_handshaking() {
+        if let Some(deadline) = deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(Error::Io(std::io::ErrorKind::TimedOut.into()));
+            }
+            socket.set_read_timeout(Some(remaining))?;
+        }
+        connection.complete_io(socket)?;
+    }
+
+    Ok(())
+}
+
+impl WebSocketStream for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        match &mut self.0 {
+            Connection::Server(stream) => Read::read(stream, buf),
+            Connection::Client(stream) => Read::read(stream, buf),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        // Flush right away, since the peer is waiting for the frame
+        match &mut self.0 {
+            Connection::Server(stream) => {
+                Write::write_all(stream, buf).and_then(|_| stream.flush())
+            }
+            Connection::Client(stream) => {
+                Write::write_all(stream, buf).and_then(|_| stream.flush())
+            }
+        }?;
+
+        Ok(buf.len())
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        self.socket().set_read_timeout(timeout)
+    }
+}
+
+/// Builds the configuration for serving wss:// from a PEM encoded certificate chain,
+/// starting with the server's own certificate, and the PEM encoded private key for it.
+pub fn server_config(
+    cert_chain_pem: &[u8],
+    private_key_pem: &[u8],
+) -> Result<Arc<ServerConfig>, Error> {
+    let cert_chain = rustls_pemfile::certs(&mut &cert_chain_pem[..]).collect::<Result<_, _>>()?;
+    let private_key = rustls_pemfile::private_key(&mut &private_key_pem[..])?
+        .ok_or_else(|| Error::Tls(rustls::Error::General("no private key found".to_owned())))?;
+
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(cert_chain, private_key)?;
+
+    Ok(Arc::new(config))
+}
+
+/// Builds the configuration for connecting to wss:// URLs, trusting the given
+/// root certificates. Useful for servers with self-signed certificates.
+pub fn client_config(root_store: RootCertStore) -> Result<Arc<ClientConfig>, Error> {
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+/// The configuration clients use unless they are given one. It trusts the Mozilla root certificates.
+pub(crate) fn default_client_config() -> Result<Arc<ClientConfig>, Error> {
+    client_config(RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    })
+}
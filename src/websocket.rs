@@ -1,16 +1,155 @@
-use crate::{frame_parser::FrameParser, http::HttpUpgradeRequest, shake_hand::shake_hand};
+#[cfg(feature = "rustls")]
+use crate::tls::{default_client_config, TlsStream};
+use crate::{
+    close::{CloseCode, CloseFrame},
+    connection_registry::{ConnectionId, ConnectionRegistry, EncodedMessage},
+    deflate::{DeflateConfig, PerMessageDeflate},
+    error::Error,
+    extension::{Extension, ExtensionChain},
+    frame_encoder::{FrameEncoder, Masking},
+    frame_parser::{DataFrame, FrameParser, Opcode},
+    handler::WebSocketHandler,
+    http::{HttpUpgradeRequest, HttpUpgradeResponse, WebSocketUrl},
+    message::{Message, MessageAssembler, Received, MAX_CONTROL_FRAME_PAYLOAD_LENGTH},
+    origin::OriginPolicy,
+    shake_hand::{
+        client_request, shake_hand, verify_response, ConnectOptions, HandshakeDecision,
+        HandshakeError, HandshakeInterceptor, SubprotocolSelector,
+    },
+};
+use std::any::Any;
 use std::io::prelude::*;
 use std::net::TcpStream;
 use std::str;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long a read may block before we check whether a heartbeat ping is due,
+/// or whether another thread has given us a command.
+static POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long we wait for the peer to answer our close frame before dropping the connection,
+/// as RFC 6455 section 7.1.1 allows.
+static CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The opening handshake request, or the response to it, has to fit in this many bytes.
+static MAX_HANDSHAKE_REQUEST_LENGTH: usize = 2048; // bytes
+
+static DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024; // bytes
+
+static READ_BUFFER_SIZE: usize = 2048; // bytes
 
 pub struct WebSocket<'a> {
-    stream: &'a mut dyn WebSocketStream,
-    frame_parser: FrameParser<'a>,
+    stream: Stream<'a>,
+    state: ConnectionState,
+    // When we stop waiting for the peer to answer our close frame.
+    close_deadline: Option<Instant>,
+    frame_parser: FrameParser,
+    message_assembler: MessageAssembler,
+    frame_encoder: FrameEncoder,
+
+    // Bytes that were read from the stream but haven't been parsed yet
+    buffered_bytes: Vec<u8>,
+
+    heartbeat: Option<Heartbeat>,
+    next_ping_at: Option<Instant>,
+    // The payload of the heartbeat ping we're waiting for a pong to, and when it was sent.
+    unanswered_ping: Option<(Vec<u8>, Instant)>,
+    pings_sent: u64,
+    round_trip_time: Option<Duration>,
+
+    subprotocol_selector: Option<SubprotocolSelector>,
+    origin_policy: Option<OriginPolicy>,
+    handshake_interceptor: Option<Arc<HandshakeInterceptor>>,
+    // Attached by the handshake interceptor.
+    user_data: Option<Box<dyn Any + Send>>,
+    // The subprotocol picked during the handshake.
+    subprotocol: Option<String>,
+
+    max_message_size: usize,
+    deflate_config: Option<DeflateConfig>,
+    // The extensions we offer to negotiate, and the ones that were negotiated.
+    extensions: Vec<Box<dyn Extension>>,
+    extension_chain: ExtensionChain,
+
+    // Commands from other threads, like the server closing the connection when it shuts down.
+    commands: Option<Receiver<Command>>,
+    // The registry of the server that accepted the connection, and the ID it has there.
+    registry: Option<(ConnectionRegistry, ConnectionId)>,
+}
+
+/// Something another thread asks the connection to do. Commands are picked up between reads.
+#[derive(PartialEq, Debug, Clone)]
+pub(crate) enum Command {
+    Close(CloseFrame),
+    Send(Arc<EncodedMessage>),
+}
+
+/// Sends a ping every `interval` and closes the connection with `CloseCode::PolicyViolation`
+/// if the peer doesn't answer with a pong within `timeout`.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum ConnectionState {
+    // The opening handshake hasn't completed yet.
+    Connecting,
+    Open,
+    // We have sent a close frame and are waiting for the peer to answer with one.
+    Closing,
+    Closed,
 }
 
 pub trait WebSocketStream {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error>;
     fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error>;
+
+    /// Makes `read` give up with `WouldBlock` or `TimedOut` after `timeout`,
+    /// so the connection can send heartbeat pings while waiting for data.
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+/// The stream is borrowed from the caller, or owned if we opened the connection ourselves in `connect`.
+enum Stream<'a> {
+    Borrowed(&'a mut dyn WebSocketStream),
+    Tcp(TcpStream),
+    #[cfg(feature = "rustls")]
+    Tls(Box<TlsStream>),
+}
+
+impl WebSocketStream for Stream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        match self {
+            Stream::Borrowed(stream) => stream.read(buf),
+            Stream::Tcp(stream) => Read::read(stream, buf),
+            #[cfg(feature = "rustls")]
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        match self {
+            Stream::Borrowed(stream) => stream.write(buf),
+            Stream::Tcp(stream) => Write::write(stream, buf),
+            #[cfg(feature = "rustls")]
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        match self {
+            Stream::Borrowed(stream) => stream.set_read_timeout(timeout),
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(feature = "rustls")]
+            Stream::Tls(stream) => stream.set_read_timeout(timeout),
+        }
+    }
 }
 
 pub struct TcpWebSocketStream<'a>(pub &'a mut TcpStream);
@@ -23,67 +162,694 @@ impl WebSocketStream for TcpWebSocketStream<'_> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
         TcpStream::write(self.0, buf)
     }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        TcpStream::set_read_timeout(self.0, timeout)
+    }
 }
 
 impl<'a> WebSocket<'a> {
     pub fn new(stream: &'a mut dyn WebSocketStream) -> WebSocket<'a> {
-        WebSocket {
-            stream: stream,
+        WebSocket::with_stream(Stream::Borrowed(stream))
+    }
+
+    /// Connects to the server at a ws:// URL and performs the opening handshake.
+    /// wss:// URLs are supported with the `rustls` feature.
+    pub fn connect(url: &str) -> Result<WebSocket<'static>, Error> {
+        WebSocket::connect_with_options(url, &ConnectOptions::default())
+    }
+
+    /// Connects like `connect`, offering the subprotocols and sending the extra headers in `options`.
+    pub fn connect_with_options(
+        url: &str,
+        options: &ConnectOptions,
+    ) -> Result<WebSocket<'static>, Error> {
+        let stream = WebSocket::open_stream(&WebSocketUrl::parse(url)?, options)?;
+
+        let mut websocket = WebSocket::with_stream(stream);
+        websocket.request_upgrade(url, options)?;
+
+        Ok(websocket)
+    }
+
+    #[cfg(feature = "rustls")]
+    fn open_stream(url: &WebSocketUrl, options: &ConnectOptions) -> Result<Stream<'static>, Error> {
+        let socket = TcpStream::connect((url.host.as_str(), url.port))?;
+        if !url.secure {
+            return Ok(Stream::Tcp(socket));
+        }
+
+        let tls_config = match &options.tls_config {
+            Some(tls_config) => Arc::clone(tls_config),
+            None => default_client_config()?,
+        };
+
+        let tls_stream = TlsStream::connect(socket, &url.host, tls_config)?;
+        Ok(Stream::Tls(Box::new(tls_stream)))
+    }
+
+    #[cfg(not(feature = "rustls"))]
+    fn open_stream(
+        url: &WebSocketUrl,
+        _options: &ConnectOptions,
+    ) -> Result<Stream<'static>, Error> {
+        if url.secure {
+            return Err(Error::Handshake(
+                "wss:// URLs need the rustls feature".to_owned(),
+            ));
+        }
+
+        Ok(Stream::Tcp(TcpStream::connect((
+            url.host.as_str(),
+            url.port,
+        ))?))
+    }
+
+    fn with_stream(stream: Stream<'a>) -> WebSocket<'a> {
+        let mut websocket = WebSocket {
+            stream,
+            state: ConnectionState::Connecting,
+            close_deadline: None,
             frame_parser: FrameParser::new(),
+            message_assembler: MessageAssembler::new(),
+            frame_encoder: FrameEncoder::new(),
+            buffered_bytes: Vec::new(),
+            heartbeat: None,
+            next_ping_at: None,
+            unanswered_ping: None,
+            pings_sent: 0,
+            round_trip_time: None,
+            subprotocol_selector: None,
+            origin_policy: None,
+            handshake_interceptor: None,
+            user_data: None,
+            subprotocol: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            deflate_config: None,
+            extensions: Vec::new(),
+            extension_chain: ExtensionChain::default(),
+            commands: None,
+            registry: None,
+        };
+        websocket.set_max_message_size(DEFAULT_MAX_MESSAGE_SIZE);
+        websocket
+    }
+
+    /// Messages and frames larger than this close the connection with `CloseCode::MessageTooBig`.
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+        self.frame_parser
+            .set_max_payload_length(max_message_size as u64);
+        self.message_assembler
+            .set_max_message_size(max_message_size);
+    }
+
+    pub fn set_heartbeat(&mut self, heartbeat: Heartbeat) {
+        self.heartbeat = Some(heartbeat);
+    }
+
+    /// Decides which of the subprotocols offered by the client we speak on this connection.
+    pub fn set_subprotocol_selector(&mut self, subprotocol_selector: SubprotocolSelector) {
+        self.subprotocol_selector = Some(subprotocol_selector);
+    }
+
+    /// Only accept upgrade requests from the origins the policy allows.
+    pub fn set_origin_policy(&mut self, origin_policy: OriginPolicy) {
+        self.origin_policy = Some(origin_policy);
+    }
+
+    /// Looks at every upgrade request before it's answered, to accept or reject it,
+    /// for example after checking a token in the query string or a cookie.
+    pub fn set_handshake_interceptor<F>(&mut self, interceptor: F)
+    where
+        F: Fn(&HttpUpgradeRequest) -> HandshakeDecision + Send + Sync + 'static,
+    {
+        self.handshake_interceptor = Some(Arc::new(interceptor));
+    }
+
+    /// Compress messages with permessage-deflate if the client offers it.
+    pub fn set_deflate(&mut self, deflate_config: DeflateConfig) {
+        self.deflate_config = Some(deflate_config);
+    }
+
+    /// Offer to negotiate the extension during the handshake. Extensions are
+    /// accepted in the order the client lists them in `Sec-WebSocket-Extensions`.
+    pub fn add_extension(&mut self, extension: Box<dyn Extension>) {
+        self.extensions.push(extension);
+    }
+
+    pub(crate) fn set_command_receiver(&mut self, commands: Receiver<Command>) {
+        self.commands = Some(commands);
+    }
+
+    pub(crate) fn set_registry(
+        &mut self,
+        registry: ConnectionRegistry,
+        connection_id: ConnectionId,
+    ) {
+        self.registry = Some((registry, connection_id));
+    }
+
+    /// The open connections of the server that accepted this connection, for
+    /// sending messages to the others. Only a `WebSocketServer` sets this.
+    pub fn registry(&self) -> Option<&ConnectionRegistry> {
+        self.registry.as_ref().map(|(registry, _)| registry)
+    }
+
+    /// The ID of this connection in `registry`.
+    pub fn connection_id(&self) -> Option<ConnectionId> {
+        self.registry
+            .as_ref()
+            .map(|(_, connection_id)| *connection_id)
+    }
+
+    /// The data the handshake interceptor attached to the connection, if it's a `T`.
+    pub fn user_data<T: Any>(&self) -> Option<&T> {
+        self.user_data.as_ref()?.downcast_ref()
+    }
+
+    pub fn user_data_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.user_data.as_mut()?.downcast_mut()
+    }
+
+    /// The subprotocol agreed on in the handshake, if any.
+    pub fn subprotocol(&self) -> Option<&str> {
+        self.subprotocol.as_deref()
+    }
+
+    /// The time it took for the peer to answer the most recent heartbeat ping.
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        self.round_trip_time
+    }
+
+    /// Performs the opening handshake and then reads frames until the connection is closed,
+    /// passing everything that happens on the connection to `handler`.
+    ///
+    /// Returns an error if the handshake fails. Errors that happen after the handshake
+    /// are passed to `WebSocketHandler::on_error` before the connection is closed.
+    pub fn open(&mut self, handler: &mut dyn WebSocketHandler) -> Result<(), Error> {
+        let request = self.accept()?;
+        handler.on_open(self, &request);
+
+        self.listen(handler);
+        Ok(())
+    }
+
+    /// Reads frames until the connection is closed, passing everything that happens on the
+    /// connection to `handler`. Clients call this after `connect`, since `open` is for servers.
+    ///
+    /// Errors are passed to `WebSocketHandler::on_error` before the connection is closed.
+    pub fn listen(&mut self, handler: &mut dyn WebSocketHandler) {
+        let close_frame = match self.read_until_closed(handler) {
+            Ok(close_frame) => close_frame,
+            Err(error) => {
+                self.fail(error.close_code(), "");
+                handler.on_error(error);
+                None
+            }
+        };
+
+        self.state = ConnectionState::Closed;
+
+        // If the peer didn't send a close frame, the connection was closed abnormally
+        handler.on_close(close_frame.unwrap_or(CloseFrame {
+            code: CloseCode::Abnormal,
+            reason: String::new(),
+        }));
+    }
+
+    /// Performs the opening handshake, after which messages can be sent.
+    ///
+    /// If the request isn't a valid upgrade request, an HTTP error response is
+    /// written to the stream and an error is returned.
+    pub fn accept(&mut self) -> Result<HttpUpgradeRequest, Error> {
+        let request = self.read_upgrade_request(None)?;
+        self.complete_upgrade(&request)?;
+
+        Ok(request)
+    }
+
+    /// The first half of `accept`: reads and parses the upgrade request, answering it
+    /// with an error response if it's malformed. The server looks at it before upgrading.
+    /// Gives up with `TimedOut` if the request hasn't arrived by the deadline, if there is one.
+    pub(crate) fn read_upgrade_request(
+        &mut self,
+        deadline: Option<Instant>,
+    ) -> Result<HttpUpgradeRequest, Error> {
+        let message = match self.read_http_message(deadline) {
+            Ok(message) => message,
+            Err(Error::Capacity(_)) => return Err(self.reject(HandshakeError::RequestTooLarge)),
+            Err(error) => return Err(error),
+        };
+
+        let request = match str::from_utf8(&message)
+            .map_err(Error::from)
+            .and_then(HttpUpgradeRequest::parse)
+        {
+            Ok(request) => request,
+            Err(Error::Capacity(_)) => return Err(self.reject(HandshakeError::RequestTooLarge)),
+            Err(error) => return Err(self.reject(HandshakeError::BadRequest(error.to_string()))),
+        };
+
+        Ok(request)
+    }
+
+    /// The second half of `accept`: checks the origin and lets the interceptor decide
+    /// about the request, then negotiates with the client and switches protocols.
+    pub(crate) fn complete_upgrade(&mut self, request: &HttpUpgradeRequest) -> Result<(), Error> {
+        if let Some(origin_policy) = &self.origin_policy {
+            if !origin_policy.allows(request) {
+                let origin = request.origin().unwrap_or_default();
+                return Err(self.reject(HandshakeError::ForbiddenOrigin(origin)));
+            }
+        }
+
+        let decision = match &self.handshake_interceptor {
+            Some(interceptor) => interceptor(request),
+            None => HandshakeDecision::accept(),
+        };
+        let (extra_headers, user_data) = match decision.into_result() {
+            Ok(accepted) => accepted,
+            Err(error) => return Err(self.reject(error)),
+        };
+
+        let mut extensions = std::mem::take(&mut self.extensions);
+        if let Some(deflate_config) = self.deflate_config {
+            let deflate = PerMessageDeflate::new(deflate_config, Some(self.max_message_size));
+            extensions.insert(0, Box::new(deflate));
+        }
+
+        let (mut response, extension_chain) =
+            match shake_hand(request, self.subprotocol_selector.as_ref(), extensions) {
+                Ok(negotiated) => negotiated,
+                Err(error) => return Err(self.reject(error)),
+            };
+        for (name, value) in extra_headers.iter() {
+            response.headers.insert(name, value);
+        }
+
+        self.write_all(&response.to_bytes())?;
+
+        self.subprotocol = response.subprotocol;
+        self.user_data = user_data;
+        self.frame_parser
+            .set_allowed_reserved_bits(extension_chain.reserved_bits());
+        self.extension_chain = extension_chain;
+        self.state = ConnectionState::Open;
+
+        Ok(())
+    }
+
+    /// Performs the client side of the opening handshake on a stream that is connected
+    /// to the server at `url`. Afterwards every frame we send is masked.
+    ///
+    /// Returns an error if the server doesn't accept the upgrade.
+    pub fn request_upgrade(
+        &mut self,
+        url: &str,
+        options: &ConnectOptions,
+    ) -> Result<HttpUpgradeResponse, Error> {
+        let request = client_request(&WebSocketUrl::parse(url)?, options);
+        self.write_all(&request.to_bytes())?;
+
+        let message = self.read_http_message(None)?;
+        let response = HttpUpgradeResponse::parse(str::from_utf8(&message)?)?;
+        verify_response(&request, &response)?;
+
+        self.subprotocol = response.subprotocol.clone();
+        self.frame_encoder.set_masking(Masking::Random);
+        self.state = ConnectionState::Open;
+
+        Ok(response)
+    }
+
+    /// Reads the request or response line and headers of an HTTP message, without the empty
+    /// line that ends them. Whatever the peer sent after that is kept for the frame parser.
+    /// Gives up with `TimedOut` once the deadline, if there is one, has passed.
+    fn read_http_message(&mut self, deadline: Option<Instant>) -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![0; MAX_HANDSHAKE_REQUEST_LENGTH];
+        let mut num_bytes = 0;
+
+        // Keep reading until we have seen the empty line that ends the message
+        let message_end_index = loop {
+            if num_bytes == bytes.len() {
+                return Err(Error::Capacity("the handshake is larger than 2048 bytes"));
+            }
+
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(Error::Io(std::io::ErrorKind::TimedOut.into()));
+                }
+                self.stream.set_read_timeout(Some(remaining))?;
+            }
+
+            let num_bytes_read = self.stream.read(&mut bytes[num_bytes..])?;
+            if num_bytes_read == 0 {
+                return Err(Error::Handshake(
+                    "the connection was closed during the handshake".to_owned(),
+                ));
+            }
+            num_bytes += num_bytes_read;
+
+            if let Some(index) = bytes[..num_bytes]
+                .windows(4)
+                .position(|window| window == b"\r\n\r\n")
+            {
+                break index;
+            }
+        };
+
+        // Frames may have been sent right after the message
+        self.buffered_bytes = bytes[message_end_index + 4..num_bytes].to_vec();
+        bytes.truncate(message_end_index);
+
+        Ok(bytes)
+    }
+
+    /// Answers the upgrade request with an error response without looking at it,
+    /// for example because the server is at its connection limit. Stops waiting
+    /// for the request at `deadline`, so slow clients can't hold up the caller.
+    pub(crate) fn refuse(&mut self, error: HandshakeError, deadline: Instant) -> Error {
+        // Closing a socket with unread data resets the connection, which can keep the client
+        // from seeing the response, so we read the request first. A client that doesn't send
+        // it in time may miss the response.
+        let _ = self.read_http_message(Some(deadline));
+
+        self.reject(error)
+    }
+
+    /// Answers a failed handshake with an HTTP error response.
+    /// Returns the error, so it can be passed on to the caller.
+    pub(crate) fn reject(&mut self, error: HandshakeError) -> Error {
+        // We are giving up on the connection anyway, so a failed write doesn't matter
+        if let Err(write_error) = self.write_all(&error.to_response().to_bytes()) {
+            log::debug!("Failed to send handshake error response: {}", write_error);
         }
+
+        error.into()
     }
 
-    pub fn open(&mut self) {
-        let mut last_was_r = false;
-        let mut saw_crlf = false;
-        let mut message_end_index = None;
+    /// Reads frames until the connection is closed.
+    /// Returns the close frame sent by the peer, if it sent one.
+    fn read_until_closed(
+        &mut self,
+        handler: &mut dyn WebSocketHandler,
+    ) -> Result<Option<CloseFrame>, Error> {
+        if let Some(heartbeat) = self.heartbeat {
+            self.next_ping_at = Some(Instant::now() + heartbeat.interval);
+        }
+        let mut polling = self.heartbeat.is_some() || self.commands.is_some();
+        if polling {
+            self.stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        }
 
-        let mut bytes = vec![0; 2048];
-        let num_bytes = self.stream.read(bytes.as_mut_slice()).unwrap();
+        let mut bytes = vec![0; READ_BUFFER_SIZE];
+        while self.state != ConnectionState::Closed {
+            if !self.keep_alive()? {
+                break;
+            }
+            self.run_commands()?;
 
-        println!("{:?}", num_bytes);
+            if let Some(close_deadline) = self.close_deadline {
+                if Instant::now() >= close_deadline {
+                    log::debug!("Peer didn't answer close frame within {:?}", CLOSE_TIMEOUT);
+                    break;
+                }
 
-        for (index, &b) in bytes.iter().enumerate() {
-            if last_was_r && b == b"\n"[0] {
-                if saw_crlf {
-                    message_end_index = Some(index);
+                // The peer may never answer, so reads mustn't block past the deadline
+                if !polling {
+                    self.stream.set_read_timeout(Some(POLL_INTERVAL))?;
+                    polling = true;
+                }
+            }
+
+            // Frames may have arrived in the same read as the handshake
+            let mut result = if self.buffered_bytes.is_empty() {
+                let num_bytes = match self.stream.read(bytes.as_mut_slice()) {
+                    Ok(num_bytes) => num_bytes,
+                    // The read timed out, so we go back and check the heartbeat and commands
+                    Err(error)
+                        if error.kind() == std::io::ErrorKind::WouldBlock
+                            || error.kind() == std::io::ErrorKind::TimedOut =>
+                    {
+                        continue
+                    }
+                    Err(error) => return Err(error.into()),
+                };
+
+                // Reading zero bytes means that the peer has closed the connection
+                if num_bytes == 0 {
                     break;
                 }
 
-                saw_crlf = true;
-                last_was_r = false;
-                continue;
+                bytes[..num_bytes].to_vec()
+            } else {
+                std::mem::take(&mut self.buffered_bytes)
+            };
+
+            let mut frames: Vec<DataFrame> = Vec::new();
+            self.frame_parser.receive(&mut result, &mut frames)?;
+
+            for frame in frames {
+                let frame = self.extension_chain.decode_frame(frame)?;
+
+                match self.message_assembler.push(frame)? {
+                    Some(Received::Message(message)) => {
+                        let message = self.extension_chain.decode_message(message)?;
+                        handler.on_message(self, Message::from_frame(message)?);
+                    }
+                    Some(Received::Control(frame)) => match frame.opcode {
+                        Opcode::Close => return self.receive_close(frame).map(Some),
+                        Opcode::Ping => {
+                            let payload = self.receive_ping(frame)?;
+                            handler.on_ping(self, &payload);
+                        }
+                        _ => {
+                            let payload = self.receive_pong(frame);
+                            handler.on_pong(self, &payload);
+                        }
+                    },
+                    None => {}
+                }
             }
+        }
+
+        Ok(None)
+    }
+
+    pub fn send_text(&mut self, text: &str) -> Result<(), Error> {
+        self.send(&Message::Text(text.to_owned()))
+    }
+
+    pub fn send_binary(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.send(&Message::Binary(bytes.to_vec()))
+    }
 
-            last_was_r = b == b"\r"[0];
+    pub fn send(&mut self, message: &Message) -> Result<(), Error> {
+        self.ensure_open()?;
 
-            if !last_was_r {
-                saw_crlf = false;
+        let message = self.extension_chain.encode_message(message.to_frame())?;
+
+        let mut bytes = Vec::new();
+        for frame in self.frame_encoder.fragment(message) {
+            let frame = self.extension_chain.encode_frame(frame)?;
+            bytes.append(&mut self.frame_encoder.encode_frame(&frame));
+        }
+
+        self.write_all(&bytes)
+    }
+
+    /// Sends a message that was encoded for several connections at once. Negotiated
+    /// extensions keep state per connection, so with those we encode it ourselves.
+    fn send_encoded(&mut self, message: &EncodedMessage) -> Result<(), Error> {
+        if !self.extension_chain.is_empty() {
+            return self.send(&message.message);
+        }
+
+        self.write_all(&message.bytes)
+    }
+
+    pub fn ping(&mut self, payload: &[u8]) -> Result<(), Error> {
+        self.send_control_frame(Opcode::Ping, payload.to_vec())
+    }
+
+    /// Starts the closing handshake by sending a close frame with the given status code and reason.
+    /// Nothing can be sent on the connection afterwards. If the peer doesn't answer with a close
+    /// frame within a second, `listen` gives up on it and reports `CloseCode::Abnormal`.
+    pub fn close(&mut self, code: CloseCode, reason: &str) -> Result<(), Error> {
+        if !code.is_allowed_on_wire() {
+            return Err(Error::InvalidCloseCode(code));
+        }
+
+        let close_frame = CloseFrame {
+            code,
+            reason: reason.to_owned(),
+        };
+        self.send_control_frame(Opcode::Close, close_frame.to_payload())?;
+        self.state = ConnectionState::Closing;
+        self.close_deadline = Some(Instant::now() + CLOSE_TIMEOUT);
+
+        Ok(())
+    }
+
+    /// Handles a close frame from the peer. If we didn't start the closing handshake,
+    /// we answer with a close frame echoing the status code.
+    fn receive_close(&mut self, frame: DataFrame) -> Result<CloseFrame, Error> {
+        let payload = frame.payload_bytes.unwrap_or_default();
+        let close_frame = CloseFrame::from_payload(&payload).map_err(Error::Protocol)?;
+
+        if self.state == ConnectionState::Open {
+            let echo = CloseFrame {
+                code: close_frame.code,
+                reason: String::new(),
+            };
+            self.send_control_frame(Opcode::Close, echo.to_payload())?;
+        }
+
+        self.state = ConnectionState::Closed;
+        Ok(close_frame)
+    }
+
+    /// Answers a ping with a pong carrying the same payload.
+    fn receive_ping(&mut self, frame: DataFrame) -> Result<Vec<u8>, Error> {
+        let payload = frame.payload_bytes.unwrap_or_default();
+
+        // Once we have sent a close frame, we can't send pongs anymore
+        if self.state == ConnectionState::Open {
+            self.send_control_frame(Opcode::Pong, payload.clone())?;
+        }
+
+        Ok(payload)
+    }
+
+    fn receive_pong(&mut self, frame: DataFrame) -> Vec<u8> {
+        let payload = frame.payload_bytes.unwrap_or_default();
+
+        // Pongs may also be sent unsolicited, in which case there's no round trip to measure
+        if let Some((ping_payload, sent_at)) = &self.unanswered_ping {
+            if *ping_payload == payload {
+                self.round_trip_time = Some(sent_at.elapsed());
+                self.unanswered_ping = None;
+            }
+        }
+
+        payload
+    }
+
+    /// Sends a heartbeat ping if one is due. Returns false if the peer didn't answer the
+    /// previous ping in time, in which case the connection is closed and should be dropped.
+    fn keep_alive(&mut self) -> Result<bool, Error> {
+        let heartbeat = match self.heartbeat {
+            Some(heartbeat) => heartbeat,
+            None => return Ok(true),
+        };
+        let now = Instant::now();
+
+        if let Some((_, sent_at)) = &self.unanswered_ping {
+            if now.duration_since(*sent_at) >= heartbeat.timeout {
+                log::debug!("Peer didn't answer ping within {:?}", heartbeat.timeout);
+                self.fail(
+                    CloseCode::PolicyViolation,
+                    "no pong within the heartbeat timeout",
+                );
+                return Ok(false);
+            }
+
+            return Ok(true);
+        }
+
+        if self
+            .next_ping_at
+            .is_some_and(|next_ping_at| now >= next_ping_at)
+        {
+            // Number the pings, so we can tell the pong to our latest ping from older or unsolicited ones
+            self.pings_sent += 1;
+            let payload = self.pings_sent.to_be_bytes().to_vec();
+
+            // We may be in the middle of closing, in which case we can't ping anymore
+            if self.state == ConnectionState::Open {
+                self.ping(&payload)?;
+                self.unanswered_ping = Some((payload, now));
+            }
+            self.next_ping_at = Some(now + heartbeat.interval);
+        }
+
+        Ok(true)
+    }
+
+    fn run_commands(&mut self) -> Result<(), Error> {
+        let commands: Vec<Command> = match &self.commands {
+            Some(commands) => commands.try_iter().collect(),
+            None => return Ok(()),
+        };
+
+        for command in commands {
+            match command {
+                // We may already be closing, in which case there's nothing left to do
+                Command::Close(close_frame) if self.state == ConnectionState::Open => {
+                    self.close(close_frame.code, &close_frame.reason)?
+                }
+                Command::Close(_) => {}
+                Command::Send(message) if self.state == ConnectionState::Open => {
+                    self.send_encoded(&message)?
+                }
+                Command::Send(_) => {}
             }
         }
 
-        let message_bytes = &bytes[..message_end_index.unwrap() + 1 - "\r\n\r\n".len()];
-        let message = str::from_utf8(message_bytes).unwrap();
+        Ok(())
+    }
 
-        let request = HttpUpgradeRequest::parse(message).unwrap();
-        let response = shake_hand(&request).unwrap();
+    /// Closes the connection because the peer misbehaved, without waiting for it to answer.
+    fn fail(&mut self, code: CloseCode, reason: &str) {
+        if self.state == ConnectionState::Open {
+            let close_frame = CloseFrame {
+                code,
+                reason: reason.to_owned(),
+            };
+            if let Err(error) = self.send_control_frame(Opcode::Close, close_frame.to_payload()) {
+                log::debug!("Failed to send close frame: {:?}", error);
+            }
+        }
 
-        let http_response = format!("HTTP/1.1 101 Switching Protocols\nUpgrade: websocket\nConnection: Upgrade\nSec-WebSocket-Accept: {}\r\n\r\n", response.sec_websocket_accept);
-        self.stream.write(http_response.as_bytes()).unwrap();
+        self.state = ConnectionState::Closed;
+    }
 
-        self.stream.write(FrameParser::create_ping_frame()).unwrap();
+    fn send_control_frame(&mut self, opcode: Opcode, payload: Vec<u8>) -> Result<(), Error> {
+        self.ensure_open()?;
 
-        // TODO - Make sure that we support HTTP Requests that are longer than 512 bytes?
-        println!("{:?}", request);
+        if payload.len() > MAX_CONTROL_FRAME_PAYLOAD_LENGTH {
+            return Err(Error::Capacity(
+                "control frames can carry at most 125 bytes of payload",
+            ));
+        }
 
-        loop {
-            let num_bytes = self.stream.read(bytes.as_mut_slice()).unwrap();
-            if num_bytes > 0 {
-                let mut result = vec![0; num_bytes];
-                println!("{} {}", num_bytes, result.capacity());
-                result.clone_from_slice(&bytes[..num_bytes]);
-                self.frame_parser.receive(&mut result);
+        let frame = self
+            .extension_chain
+            .encode_frame(DataFrame::new(true, opcode, payload))?;
+        let bytes = self.frame_encoder.encode_frame(&frame);
+        self.write_all(&bytes)
+    }
+
+    fn ensure_open(&self) -> Result<(), Error> {
+        match self.state {
+            ConnectionState::Open => Ok(()),
+            _ => Err(Error::ConnectionClosed),
+        }
+    }
+
+    // WebSocketStream::write may only write part of the buffer, so we keep writing until everything is sent.
+    fn write_all(&mut self, mut bytes: &[u8]) -> Result<(), Error> {
+        while !bytes.is_empty() {
+            let num_bytes = self.stream.write(bytes)?;
+            if num_bytes == 0 {
+                return Err(Error::Io(std::io::ErrorKind::WriteZero.into()));
             }
+
+            bytes = &bytes[num_bytes..];
         }
+
+        Ok(())
     }
 }
@@ -1,36 +1,384 @@
-use std::{net::{TcpListener, TcpStream}};
+use std::{io::ErrorKind, panic::{self, AssertUnwindSafe}, net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs}, sync::{Arc, mpsc::{self, Receiver, SyncSender, TrySendError}}, thread, time::{Duration, Instant}};
 
-use crate::{ThreadPool, WebSocket, websocket::TcpWebSocketStream};
+use crate::{ConnectionRegistry, DeflateConfig, Error, Extension, HandshakeDecision, HttpUpgradeRequest, IntoRouter, OriginPolicy, Router, SubprotocolSelector, ThreadPool, WebSocket, WebSocketStream, server_handle::{COMMAND_QUEUE_SIZE, Registration, ServerHandle, ServerState}, shake_hand::{HandshakeError, HandshakeInterceptor}, websocket::{Command, Heartbeat, TcpWebSocketStream}};
+#[cfg(feature = "rustls")]
+use crate::tls::TlsStream;
 
+type ExtensionFactory = dyn Fn() -> Box<dyn Extension> + Send + Sync;
+
+/// How long the accept loop sleeps when there are no connections waiting, before it
+/// checks again. This bounds how long a shutdown waits for the loop to stop.
+static ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long a connection refused with 503 has to send its request before it's answered anyway.
+/// Refusals are answered one at a time, so this bounds how long one can hold up the others.
+static REFUSAL_READ_DEADLINE: Duration = Duration::from_millis(100);
+
+/// How long a connection has to complete the TLS handshake and send its upgrade request,
+/// unless the server sets a different timeout.
+static DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many refused connections may wait to be answered with 503. Any more are closed without an answer.
+static REFUSAL_QUEUE_SIZE: usize = 16;
+
+/// What the server does with new connections once it's at its connection limit.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum OverflowPolicy {
+    // Answer the handshake with 503 Service Unavailable.
+    Reject,
+
+    // Close the connection without answering.
+    Close,
+
+    // Let up to this many connections wait until others close. Any more are rejected with 503.
+    Queue(usize),
+}
+
+struct ConnectionLimit {
+    max_connections: usize,
+    policy: OverflowPolicy,
+}
+
+impl ConnectionLimit {
+    /// How many connections may be admitted at once, counting the ones waiting for a slot.
+    fn capacity(&self) -> usize {
+        match self.policy {
+            OverflowPolicy::Queue(queue_size) => self.max_connections.saturating_add(queue_size),
+            _ => self.max_connections,
+        }
+    }
+}
+
+/// The settings every connection is opened with.
+#[derive(Clone, Default)]
+struct ConnectionSettings {
+    heartbeat: Option<Heartbeat>,
+    subprotocol_selector: Option<SubprotocolSelector>,
+    origin_policy: Option<OriginPolicy>,
+    handshake_interceptor: Option<Arc<HandshakeInterceptor>>,
+    deflate: Option<DeflateConfig>,
+    extension_factories: Vec<Arc<ExtensionFactory>>,
+    #[cfg(feature = "rustls")]
+    tls_config: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl ConnectionSettings {
+    fn apply(self, websocket: &mut WebSocket<'_>) {
+        if let Some(heartbeat) = self.heartbeat {
+            websocket.set_heartbeat(heartbeat);
+        }
+        if let Some(subprotocol_selector) = self.subprotocol_selector {
+            websocket.set_subprotocol_selector(subprotocol_selector);
+        }
+        if let Some(origin_policy) = self.origin_policy {
+            websocket.set_origin_policy(origin_policy);
+        }
+        if let Some(handshake_interceptor) = self.handshake_interceptor {
+            websocket.set_handshake_interceptor(move |request| handshake_interceptor(request));
+        }
+        if let Some(deflate) = self.deflate {
+            websocket.set_deflate(deflate);
+        }
+        for extension_factory in self.extension_factories {
+            websocket.add_extension(extension_factory());
+        }
+    }
+}
+
+/// Serves WebSocket connections on a pool of threads. Connections that fail are dropped
+/// and reported through the `log` crate, at debug level since clients cause most failures.
 pub struct WebSocketServer {
-    port: usize,
+    listener: TcpListener,
     num_threads: usize,
+    settings: ConnectionSettings,
+    router: Arc<Router>,
+    connection_limit: Option<ConnectionLimit>,
+    handshake_timeout: Duration,
+    state: Arc<ServerState>,
 }
 
 impl WebSocketServer {
-    pub fn new(port: usize, num_threads: usize) -> WebSocketServer {
+    /// Binds to `addr`, like "0.0.0.0:8080" or "[::]:8080", and creates a server that gets a handler
+    /// for every accepted connection from `handlers`. That's either a handler factory, which serves
+    /// every path, or a `Router`, which picks a handler factory by the requested path.
+    /// Bind to port 0 to let the operating system pick a free port, and read it with `local_addr`.
+    pub fn bind<A, R>(addr: A, num_threads: usize, handlers: R) -> Result<WebSocketServer, Error>
+    where
+        A: ToSocketAddrs,
+        R: IntoRouter,
+    {
+        Ok(WebSocketServer::from_listener(TcpListener::bind(addr)?, num_threads, handlers))
+    }
+
+    /// Creates a server that accepts connections on a listener that is already bound,
+    /// for example one handed over by socket activation.
+    pub fn from_listener<R: IntoRouter>(listener: TcpListener, num_threads: usize, handlers: R) -> WebSocketServer {
         WebSocketServer {
-            port,
+            listener,
             num_threads,
+            settings: ConnectionSettings::default(),
+            router: Arc::new(handlers.into_router()),
+            connection_limit: None,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            state: Arc::new(ServerState::default()),
         }
     }
 
-    pub fn start(&self) {
-        let listener = TcpListener::bind(format!("127.0.0.1:{}", self.port)).unwrap();
+    /// Ping every connection periodically, and drop the ones that stop answering.
+    pub fn set_heartbeat(&mut self, heartbeat: Heartbeat) {
+        self.settings.heartbeat = Some(heartbeat);
+    }
+
+    /// Negotiate a subprotocol with every client. Handlers can read the
+    /// chosen one with `WebSocket::subprotocol`.
+    pub fn set_subprotocol_selector(&mut self, subprotocol_selector: SubprotocolSelector) {
+        self.settings.subprotocol_selector = Some(subprotocol_selector);
+    }
+
+    /// Only accept upgrade requests from the origins the policy allows, answering the others
+    /// with 403 Forbidden. Browser clients should be protected with one, since browsers let any
+    /// page open a WebSocket to any server.
+    pub fn set_origin_policy(&mut self, origin_policy: OriginPolicy) {
+        self.settings.origin_policy = Some(origin_policy);
+    }
+
+    /// Look at every upgrade request before it's answered, to accept or reject it. Requests to paths
+    /// without a route, or from origins the origin policy doesn't allow, are answered before this.
+    /// Handlers read the user data the interceptor attaches with `WebSocket::user_data`.
+    pub fn set_handshake_interceptor<F>(&mut self, interceptor: F)
+    where
+        F: Fn(&HttpUpgradeRequest) -> HandshakeDecision + Send + Sync + 'static,
+    {
+        self.settings.handshake_interceptor = Some(Arc::new(interceptor));
+    }
+
+    /// Compress messages with permessage-deflate for clients that offer it.
+    pub fn set_deflate(&mut self, deflate_config: DeflateConfig) {
+        self.settings.deflate = Some(deflate_config);
+    }
+
+    /// Offer an extension to every client. `extension_factory` is called for
+    /// every connection, so each connection gets its own extension state.
+    pub fn add_extension<F, E>(&mut self, extension_factory: F)
+    where
+        F: Fn() -> E + Send + Sync + 'static,
+        E: Extension + 'static,
+    {
+        self.settings.extension_factories.push(Arc::new(move || Box::new(extension_factory())));
+    }
+
+    /// Serve wss:// instead of ws://. `rust_websocket::server_config` builds the
+    /// configuration from a PEM encoded certificate chain and private key.
+    #[cfg(feature = "rustls")]
+    pub fn set_tls(&mut self, tls_config: Arc<rustls::ServerConfig>) {
+        self.settings.tls_config = Some(tls_config);
+    }
+
+    /// Serve at most `max_connections` connections at once, and handle the ones beyond
+    /// that according to `policy`. Without a limit, connections beyond the number of
+    /// threads wait for a free thread for as long as it takes. Queued connections hold a thread
+    /// while they wait for a served one to close, and aren't answered until then.
+    ///
+    /// Every connection occupies a thread while it is open, so `max_connections` shouldn't
+    /// exceed the number of threads. With TLS, refused connections are closed rather than
+    /// answered with 503, since answering would mean a TLS handshake for each of them.
+    pub fn set_max_connections(&mut self, max_connections: usize, policy: OverflowPolicy) {
+        self.connection_limit = Some(ConnectionLimit { max_connections, policy });
+    }
+
+    /// Drop connections that haven't completed the TLS handshake and sent their upgrade request
+    /// within `timeout`, so idle sockets can't hold on to a thread. Defaults to 10 seconds.
+    /// Queued connections get the timeout once they're let through.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// The connections of this server, for sending them messages from outside a handler.
+    /// Handlers get it from `WebSocket::registry`.
+    pub fn registry(&self) -> ConnectionRegistry {
+        ConnectionRegistry::new(Arc::clone(&self.state))
+    }
+
+    /// The address the server is listening on, with the actual port if it was bound to port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Starts accepting connections on a background thread. The returned handle shuts the server down.
+    pub fn start(self) -> Result<ServerHandle, Error> {
+        // The accept loop has to notice when the server is shut down, so it can't block on accept
+        self.listener.set_nonblocking(true)?;
+        let local_addr = self.listener.local_addr()?;
+
+        let state = Arc::clone(&self.state);
+        let accept_state = Arc::clone(&state);
+        let accept_thread = thread::spawn(move || self.accept_connections(&accept_state));
+
+        Ok(ServerHandle::new(local_addr, state, accept_thread))
+    }
+
+    /// Accepts connections until the server is shut down. Returns the pool running the
+    /// connections, so the shutdown can wait for them.
+    fn accept_connections(self, state: &Arc<ServerState>) -> ThreadPool {
         let pool = ThreadPool::new(self.num_threads);
+        let refusals = self.connection_limit.as_ref().map(|_| WebSocketServer::spawn_refusal_thread());
 
-        for stream in listener.incoming() {
-            let stream = stream.unwrap();
+        while !state.is_shutting_down() {
+            // A failed accept only affects that one connection
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                    continue;
+                }
+                Err(error) => {
+                    log::warn!("Failed to accept connection: {}", error);
+                    continue;
+                }
+            };
+            if let (Some(connection_limit), Some(refusals)) = (&self.connection_limit, &refusals) {
+                if state.admitted_count() >= connection_limit.capacity() {
+                    state.record_refusal();
+                    self.refuse(stream, connection_limit.policy, refusals);
+                    continue;
+                }
+            }
 
-            pool.execute(|| {
-                WebSocketServer::handle_connection(stream);
+            let admission = state.admit();
+            let max_connections = self.connection_limit.as_ref().map(|connection_limit| connection_limit.max_connections);
+            let handshake_timeout = self.handshake_timeout;
+            let settings = self.settings.clone();
+            let router = Arc::clone(&self.router);
+            let state = Arc::clone(state);
+
+            pool.execute(move || {
+                // Queued connections wait here, unanswered, until a served one closes
+                let serving_slot = match max_connections {
+                    Some(max_connections) => match state.occupy_serving_slot(max_connections) {
+                        Some(serving_slot) => Some(serving_slot),
+                        None => return,
+                    },
+                    None => None,
+                };
+
+                let handshake_deadline = Instant::now() + handshake_timeout;
+
+                // A panicking handler or interceptor only takes down its own connection, not the worker
+                let served = panic::catch_unwind(AssertUnwindSafe(|| WebSocketServer::handle_connection(stream, handshake_deadline, settings, &router, &state)));
+                if served.is_err() {
+                    log::error!("Dropping connection, because its handler panicked");
+                }
+                drop(serving_slot);
+                drop(admission);
             });
         }
+
+        pool
     }
 
-    fn handle_connection(mut stream: TcpStream) {
-        let mut wrapped_stream = TcpWebSocketStream(&mut stream);
-        let mut websocket = WebSocket::new(&mut wrapped_stream);
-        websocket.open();
+    /// Turns away a connection the server has no room for, handing it to the refusal thread
+    /// unless the policy is to close it.
+    fn refuse(&self, stream: TcpStream, policy: OverflowPolicy, refusals: &SyncSender<(TcpStream, Instant)>) {
+        #[cfg(feature = "rustls")]
+        let policy = if self.settings.tls_config.is_some() { OverflowPolicy::Close } else { policy };
+
+        let stream = if policy == OverflowPolicy::Close {
+            stream
+        } else {
+            match refusals.try_send((stream, Instant::now() + REFUSAL_READ_DEADLINE)) {
+                Ok(()) => return,
+                // The refusal thread is behind, so this connection is closed without an answer
+                Err(TrySendError::Full((stream, _))) | Err(TrySendError::Disconnected((stream, _))) => stream,
+            }
+        };
+
+        // The connection is being dropped anyway, so a failed shutdown doesn't matter
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    /// Answers refused connections with 503 on a thread of their own, so clients that are slow
+    /// to send their request can't hold up the accept loop. The thread stops with the accept loop.
+    fn spawn_refusal_thread() -> SyncSender<(TcpStream, Instant)> {
+        let (refusals, refused_streams) = mpsc::sync_channel::<(TcpStream, Instant)>(REFUSAL_QUEUE_SIZE);
+        thread::spawn(move || {
+            for (mut stream, deadline) in refused_streams {
+                // Accepted sockets inherit non-blocking mode from the listener on some platforms
+                if stream.set_nonblocking(false).is_ok() {
+                    WebSocket::new(&mut TcpWebSocketStream(&mut stream)).refuse(HandshakeError::Overloaded, deadline);
+                }
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        });
+        refusals
+    }
+
+    fn handle_connection(mut stream: TcpStream, handshake_deadline: Instant, settings: ConnectionSettings, router: &Router, state: &Arc<ServerState>) {
+        // Accepted sockets inherit non-blocking mode from the listener on some platforms
+        let prepared = stream.set_nonblocking(false).and_then(|_| Ok((stream.try_clone()?, stream.peer_addr()?)));
+        let (socket, remote_addr) = match prepared {
+            Ok(prepared) => prepared,
+            Err(error) => {
+                log::debug!("Dropping connection: {}", error);
+                return;
+            }
+        };
+
+        let (commands, command_receiver) = mpsc::sync_channel(COMMAND_QUEUE_SIZE);
+        let registration = state.register(socket, remote_addr, commands);
+        if state.is_shutting_down() {
+            return;
+        }
+
+        #[cfg(feature = "rustls")]
+        if let Some(tls_config) = settings.tls_config.clone() {
+            match TlsStream::accept_before(stream, tls_config, Some(handshake_deadline)) {
+                Ok(mut tls_stream) => WebSocketServer::serve(&mut tls_stream, handshake_deadline, settings, router, &registration, command_receiver),
+                Err(error) => log::debug!("Dropping connection: {}", error),
+            }
+            return;
+        }
+
+        WebSocketServer::serve(&mut TcpWebSocketStream(&mut stream), handshake_deadline, settings, router, &registration, command_receiver);
     }
-}
\ No newline at end of file
+
+    fn serve(stream: &mut dyn WebSocketStream, handshake_deadline: Instant, settings: ConnectionSettings, router: &Router, registration: &Registration, commands: Receiver<Command>) {
+        let mut websocket = WebSocket::new(stream);
+        settings.apply(&mut websocket);
+        websocket.set_command_receiver(commands);
+        websocket.set_registry(registration.registry(), registration.id());
+
+        // Like WebSocket::open, but the request is routed before it's upgraded, and the
+        // connection joins the registry before the handler hears about it, so on_open
+        // can already send to it
+        let mut request = match websocket.read_upgrade_request(Some(handshake_deadline)) {
+            Ok(request) => request,
+            Err(error) => {
+                log::debug!("Dropping connection: {}", error);
+                return;
+            }
+        };
+
+        let handler_factory = match router.route(&request.path) {
+            Some((handler_factory, path_params)) => {
+                request.path_params = path_params;
+                handler_factory
+            }
+            None => {
+                let error = websocket.reject(HandshakeError::NotFound(request.path.clone()));
+                log::debug!("Dropping connection: {}", error);
+                return;
+            }
+        };
+
+        if let Err(error) = websocket.complete_upgrade(&request) {
+            log::debug!("Dropping connection: {}", error);
+            return;
+        }
+        registration.mark_open(&request.path);
+
+        let mut handler = handler_factory();
+
+        handler.on_open(&mut websocket, &request);
+        websocket.listen(handler.as_mut());
+    }
+}
@@ -0,0 +1,158 @@
+#![cfg(feature = "rustls")]
+
+use rust_websocket::rustls::RootCertStore;
+use rust_websocket::{client_config,server_config,CloseCode,CloseFrame,ConnectOptions,Error,HttpUpgradeRequest,Message,TlsStream,WebSocket,WebSocketHandler,WebSocketServer};
+use std::io::Read;
+use std::net::{TcpListener,TcpStream};
+use std::thread;
+use std::time::{Duration,Instant};
+
+#[derive(Default)]
+struct EchoHandler {
+  messages: Vec<Message>,
+  close_frame: Option<CloseFrame>,
+}
+
+impl WebSocketHandler for EchoHandler {
+  fn on_open(&mut self, socket: &mut WebSocket<'_>, _request: &HttpUpgradeRequest) {
+    socket.send_text("Welcome").unwrap();
+  }
+
+  fn on_message(&mut self, socket: &mut WebSocket<'_>, message: Message) {
+    socket.send(&message).unwrap();
+    self.messages.push(message);
+  }
+
+  fn on_close(&mut self, close_frame: CloseFrame) {
+    self.close_frame = Some(close_frame);
+  }
+}
+
+#[derive(Default)]
+struct ClientHandler {
+  messages: Vec<Message>,
+  close_frame: Option<CloseFrame>,
+}
+
+impl WebSocketHandler for ClientHandler {
+  fn on_message(&mut self, socket: &mut WebSocket<'_>, message: Message) {
+    if message == Message::Text("Hello".to_owned()) {
+      socket.close(CloseCode::Normal, "").unwrap();
+    }
+    self.messages.push(message);
+  }
+
+  fn on_close(&mut self, close_frame: CloseFrame) {
+    self.close_frame = Some(close_frame);
+  }
+}
+
+// A certificate for localhost, signed by itself, and a client configuration that trusts it.
+fn self_signed_certificate() -> (String, String, RootCertStore) {
+  let certified_key = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+
+  let mut root_store = RootCertStore::empty();
+  root_store.add(certified_key.cert.der().clone()).unwrap();
+
+  (certified_key.cert.pem(), certified_key.key_pair.serialize_pem(), root_store)
+}
+
+#[test]
+fn it_exchanges_messages_over_tls() {
+  let (cert_pem, key_pem, root_store) = self_signed_certificate();
+  let tls_config = server_config(cert_pem.as_bytes(), key_pem.as_bytes()).unwrap();
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let port = listener.local_addr().unwrap().port();
+
+  let server = thread::spawn(move || {
+    let mut stream = TlsStream::accept(listener.accept().unwrap().0, tls_config).unwrap();
+    let mut handler = EchoHandler::default();
+    WebSocket::new(&mut stream).open(&mut handler).unwrap();
+    handler
+  });
+
+  let options = ConnectOptions { tls_config: Some(client_config(root_store).unwrap()), ..ConnectOptions::default() };
+  let mut ws = WebSocket::connect_with_options(&format!("wss://localhost:{}/", port), &options).unwrap();
+  ws.send_text("Hello").unwrap();
+
+  let mut client_handler = ClientHandler::default();
+  ws.listen(&mut client_handler);
+
+  assert_eq!(client_handler.messages, vec![Message::Text("Welcome".to_owned()), Message::Text("Hello".to_owned())]);
+  assert_eq!(client_handler.close_frame.map(|close_frame| close_frame.code), Some(CloseCode::Normal));
+
+  let server_handler = server.join().unwrap();
+  assert_eq!(server_handler.messages, vec![Message::Text("Hello".to_owned())]);
+  assert_eq!(server_handler.close_frame.map(|close_frame| close_frame.code), Some(CloseCode::Normal));
+}
+
+#[test]
+fn it_refuses_servers_with_untrusted_certificates() {
+  let (cert_pem, key_pem, _) = self_signed_certificate();
+  let tls_config = server_config(cert_pem.as_bytes(), key_pem.as_bytes()).unwrap();
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let port = listener.local_addr().unwrap().port();
+
+  let server = thread::spawn(move || {
+    assert!(TlsStream::accept(listener.accept().unwrap().0, tls_config).is_err());
+  });
+
+  // Without a configuration, only certificates signed by the Mozilla root certificates are trusted
+  assert!(matches!(WebSocket::connect(&format!("wss://localhost:{}/", port)), Err(Error::Io(_))));
+  server.join().unwrap();
+}
+
+#[test]
+fn it_rejects_invalid_certificates_and_keys() {
+  let (cert_pem, key_pem, _) = self_signed_certificate();
+
+  assert!(matches!(server_config(cert_pem.as_bytes(), b""), Err(Error::Tls(_))));
+  assert!(matches!(server_config(b"", key_pem.as_bytes()), Err(Error::Tls(_))));
+}
+
+#[test]
+fn it_serves_wss_from_the_server() {
+  let (cert_pem, key_pem, root_store) = self_signed_certificate();
+
+  let mut server = WebSocketServer::bind("127.0.0.1:0", 2, EchoHandler::default).unwrap();
+  server.set_tls(server_config(cert_pem.as_bytes(), key_pem.as_bytes()).unwrap());
+  let handle = server.start().unwrap();
+  let port = handle.local_addr().port();
+
+  let options = ConnectOptions { tls_config: Some(client_config(root_store).unwrap()), ..ConnectOptions::default() };
+  let mut ws = WebSocket::connect_with_options(&format!("wss://localhost:{}/", port), &options).unwrap();
+  ws.send_text("Hello").unwrap();
+
+  let mut client_handler = ClientHandler::default();
+  ws.listen(&mut client_handler);
+
+  assert_eq!(client_handler.messages, vec![Message::Text("Welcome".to_owned()), Message::Text("Hello".to_owned())]);
+  handle.shutdown(Duration::from_secs(1));
+}
+
+#[test]
+fn it_drops_connections_that_do_not_complete_the_tls_handshake_in_time() {
+  let (cert_pem, key_pem, _) = self_signed_certificate();
+
+  let mut server = WebSocketServer::bind("127.0.0.1:0", 2, EchoHandler::default).unwrap();
+  server.set_tls(server_config(cert_pem.as_bytes(), key_pem.as_bytes()).unwrap());
+  server.set_handshake_timeout(Duration::from_millis(200));
+  let handle = server.start().unwrap();
+
+  // Never starts the TLS handshake
+  let mut silent = TcpStream::connect(handle.local_addr()).unwrap();
+  let started = Instant::now();
+  let mut received = Vec::new();
+  silent.read_to_end(&mut received).unwrap();
+  assert!(received.is_empty());
+  assert!(started.elapsed() < Duration::from_secs(5), "took {:?}", started.elapsed());
+
+  let deadline = Instant::now() + Duration::from_secs(5);
+  while handle.connection_count() > 0 {
+    assert!(Instant::now() < deadline, "the connection still holds its slot");
+    thread::sleep(Duration::from_millis(10));
+  }
+  handle.shutdown(Duration::from_secs(1));
+}
@@ -1,11 +1,16 @@
-use rust_websocket::{WebSocket,WebSocketStream};
+use rust_websocket::{CloseCode,CloseFrame,ConnectOptions,DataFrame,DeflateConfig,Error,Extension,ExtensionOffer,ExtensionParams,FrameEncoder,HandshakeDecision,Heartbeat,HttpUpgradeRequest,Masking,Message,Opcode,OriginPolicy,OverflowPolicy,Router,ServerHandle,SubprotocolSelector,WebSocket,WebSocketHandler,WebSocketServer,WebSocketStream};
 use std::cmp;
+use std::io::{ErrorKind,Read,Write};
+use std::net::{SocketAddr,TcpListener,TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration,Instant};
 
 #[derive(Debug)]
 struct FakeStream {
   message: Vec<u8>,
   cursor: usize,
-  written: Option<Vec<u8>>,
+  written: Vec<u8>,
 }
 
 impl FakeStream {
@@ -14,7 +19,7 @@ impl FakeStream {
     FakeStream {
       message,
       cursor: 0,
-      written: None,
+      written: Vec::new(),
     }
   }
 }
@@ -34,11 +39,56 @@ impl WebSocketStream for FakeStream {
   }
 
   fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
-    self.written = Some(buf.to_vec());
+    self.written.extend_from_slice(buf);
     Ok(buf.len())
   }
 }
 
+#[derive(Debug)]
+enum Event {
+  Open(String),
+  Message(Message),
+  Ping(Vec<u8>),
+  Pong(Vec<u8>),
+  Close(CloseFrame),
+  Error(Error),
+}
+
+#[derive(Default)]
+struct RecordingHandler {
+  events: Vec<Event>,
+}
+
+impl WebSocketHandler for RecordingHandler {
+  fn on_open(&mut self, _socket: &mut WebSocket<'_>, request: &HttpUpgradeRequest) {
+    self.events.push(Event::Open(request.path.clone()));
+  }
+
+  fn on_message(&mut self, socket: &mut WebSocket<'_>, message: Message) {
+    // Echo text messages, so we can test sending from inside the callbacks
+    if let Message::Text(text) = &message {
+      socket.send_text(text).unwrap();
+    }
+    self.events.push(Event::Message(message));
+  }
+
+  fn on_ping(&mut self, _socket: &mut WebSocket<'_>, payload: &[u8]) {
+    self.events.push(Event::Ping(payload.to_vec()));
+  }
+
+  fn on_pong(&mut self, _socket: &mut WebSocket<'_>, payload: &[u8]) {
+    self.events.push(Event::Pong(payload.to_vec()));
+  }
+
+  fn on_close(&mut self, close_frame: CloseFrame) {
+    self.events.push(Event::Close(close_frame));
+  }
+
+  fn on_error(&mut self, error: Error) {
+    self.events.push(Event::Error(error));
+  }
+}
+
 #[test]
 fn it_works() {
   let handshake_message = b"GET / HTTP/1.1\r\nHost: example.com:8000\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
@@ -46,8 +96,1005 @@ fn it_works() {
   let mut fake_stream = FakeStream::new(handshake_message.to_vec());
   let mut ws = WebSocket::new(&mut fake_stream);
 
-  ws.open();
+  ws.open(&mut RecordingHandler::default()).unwrap();
 
-  let handshake_response = b"HTTP/1.1 101 Switching Protocols\nUpgrade: websocket\nConnection: Upgrade\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
-  assert_eq!(fake_stream.written.unwrap(), handshake_response);
+  let handshake_response = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+  assert_eq!(fake_stream.written, handshake_response.to_vec());
 } 
+
+
+static HANDSHAKE_MESSAGE: &[u8] = b"GET / HTTP/1.1\r\nHost: example.com:8000\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+
+#[test]
+fn it_sends_messages_after_accepting() {
+  let mut fake_stream = FakeStream::new(HANDSHAKE_MESSAGE.to_vec());
+  let mut ws = WebSocket::new(&mut fake_stream);
+
+  ws.accept().unwrap();
+  ws.send_text("Hi").unwrap();
+  ws.send_binary(&[1, 2, 3]).unwrap();
+  ws.ping(b"ping").unwrap();
+  ws.close(CloseCode::Normal, "bye").unwrap();
+
+  let expected_frames = [
+    vec![0b10000001, 2], b"Hi".to_vec(),
+    vec![0b10000010, 3, 1, 2, 3],
+    vec![0b10001001, 4], b"ping".to_vec(),
+    vec![0b10001000, 5, 0x03, 0xE8], b"bye".to_vec(),
+  ].concat();
+  assert!(fake_stream.written.ends_with(&expected_frames));
+}
+
+#[test]
+fn it_refuses_to_send_before_the_handshake() {
+  let mut fake_stream = FakeStream::new(Vec::new());
+  let mut ws = WebSocket::new(&mut fake_stream);
+
+  assert!(matches!(ws.send_text("Hi"), Err(Error::ConnectionClosed)));
+  assert!(fake_stream.written.is_empty());
+}
+
+#[test]
+fn it_refuses_to_send_after_closing() {
+  let mut fake_stream = FakeStream::new(HANDSHAKE_MESSAGE.to_vec());
+  let mut ws = WebSocket::new(&mut fake_stream);
+
+  ws.accept().unwrap();
+  ws.close(CloseCode::Normal, "").unwrap();
+
+  assert!(matches!(ws.send_binary(&[1]), Err(Error::ConnectionClosed)));
+  assert!(matches!(ws.ping(&[]), Err(Error::ConnectionClosed)));
+  assert!(matches!(ws.close(CloseCode::Normal, ""), Err(Error::ConnectionClosed)));
+}
+
+#[test]
+fn it_refuses_control_frames_with_too_long_payload() {
+  let mut fake_stream = FakeStream::new(HANDSHAKE_MESSAGE.to_vec());
+  let mut ws = WebSocket::new(&mut fake_stream);
+
+  ws.accept().unwrap();
+
+  assert!(matches!(ws.ping(&[0; 126]), Err(Error::Capacity(_))));
+}
+
+#[test]
+fn it_echoes_close_frame_from_peer() {
+  let close_frame = [vec![0b10001000, 0b10000101, 0, 0, 0, 0, 0x03, 0xE9], b"bye".to_vec()].concat();
+  let mut fake_stream = FakeStream::new([HANDSHAKE_MESSAGE, &close_frame[..]].concat());
+  let mut ws = WebSocket::new(&mut fake_stream);
+
+  let mut handler = RecordingHandler::default();
+  ws.open(&mut handler).unwrap();
+
+  assert!(matches!(handler.events.last(), Some(Event::Close(close_frame)) if *close_frame == CloseFrame { code: CloseCode::GoingAway, reason: "bye".to_owned() }));
+  assert!(matches!(ws.send_text("Hi"), Err(Error::ConnectionClosed)));
+  assert!(fake_stream.written.ends_with(&[0b10001000, 2, 0x03, 0xE9]));
+}
+
+#[test]
+fn it_closes_with_protocol_error_on_invalid_close_code() {
+  let close_frame = [0b10001000, 0b10000010, 0, 0, 0, 0, 0x03, 0xEE];
+  let mut fake_stream = FakeStream::new([HANDSHAKE_MESSAGE, &close_frame[..]].concat());
+  let mut ws = WebSocket::new(&mut fake_stream);
+
+  let mut handler = RecordingHandler::default();
+  ws.open(&mut handler).unwrap();
+
+  assert!(matches!(handler.events[1], Event::Error(Error::Protocol(CloseCode::ProtocolError))));
+  assert!(matches!(&handler.events[2], Event::Close(close_frame) if close_frame.code == CloseCode::Abnormal));
+  assert!(fake_stream.written.ends_with(&[0b10001000, 2, 0x03, 0xEA]));
+}
+
+#[test]
+fn it_refuses_to_send_reserved_close_codes() {
+  let mut fake_stream = FakeStream::new(HANDSHAKE_MESSAGE.to_vec());
+  let mut ws = WebSocket::new(&mut fake_stream);
+
+  ws.accept().unwrap();
+
+  assert!(matches!(ws.close(CloseCode::NoStatus, ""), Err(Error::InvalidCloseCode(_))));
+  assert!(matches!(ws.close(CloseCode::Abnormal, ""), Err(Error::InvalidCloseCode(_))));
+  ws.send_text("still open").unwrap();
+}
+
+#[test]
+fn it_answers_ping_with_pong() {
+  let ping_frame = [0b10001001, 0b10000010, 0, 0, 0, 0, b'h', b'i'];
+  let mut fake_stream = FakeStream::new([HANDSHAKE_MESSAGE, &ping_frame[..]].concat());
+  let mut ws = WebSocket::new(&mut fake_stream);
+
+  let mut handler = RecordingHandler::default();
+  ws.open(&mut handler).unwrap();
+
+  assert!(matches!(&handler.events[1], Event::Ping(payload) if payload == b"hi"));
+  assert!(fake_stream.written.ends_with(&[0b10001010, 2, b'h', b'i']));
+}
+
+/// A stream that times out reads once it runs out of data, and answers
+/// a limited number of pings with pongs, like a peer that eventually hangs.
+struct UnresponsiveStream {
+  inner: FakeStream,
+  pongs_left: usize,
+}
+
+impl WebSocketStream for UnresponsiveStream {
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+    if self.inner.cursor == self.inner.message.len() {
+      thread::sleep(Duration::from_millis(5));
+      return Err(std::io::ErrorKind::TimedOut.into());
+    }
+
+    self.inner.read(buf)
+  }
+
+  fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+    if buf[0] == 0b10001001 && self.pongs_left > 0 {
+      self.pongs_left -= 1;
+
+      let payload = &buf[2..];
+      self.inner.message.extend_from_slice(&[0b10001010, 0b10000000 | payload.len() as u8, 0, 0, 0, 0]);
+      self.inner.message.extend_from_slice(payload);
+    }
+
+    self.inner.write(buf)
+  }
+}
+
+#[test]
+fn it_sends_heartbeat_pings_and_closes_when_pongs_stop() {
+  let mut stream = UnresponsiveStream { inner: FakeStream::new(HANDSHAKE_MESSAGE.to_vec()), pongs_left: 1 };
+  let mut ws = WebSocket::new(&mut stream);
+  ws.set_heartbeat(Heartbeat { interval: Duration::from_millis(0), timeout: Duration::from_millis(50) });
+
+  let mut handler = RecordingHandler::default();
+  ws.open(&mut handler).unwrap();
+
+  assert!(ws.round_trip_time().is_some());
+  assert!(matches!(&handler.events[1], Event::Pong(payload) if *payload == 1u64.to_be_bytes()));
+  assert!(matches!(&handler.events[2], Event::Close(close_frame) if close_frame.code == CloseCode::Abnormal));
+  assert!(matches!(ws.send_text("Hi"), Err(Error::ConnectionClosed)));
+
+  // The first ping was answered, the second wasn't, so the connection was closed with a policy violation
+  let written = &stream.inner.written;
+  let first_ping = [0b10001001, 8, 0, 0, 0, 0, 0, 0, 0, 1];
+  let second_ping = [0b10001001, 8, 0, 0, 0, 0, 0, 0, 0, 2];
+  let reason = b"no pong within the heartbeat timeout";
+  let close = [&[0b10001000, 2 + reason.len() as u8, 0x03, 0xF0][..], reason].concat();
+  assert!(written.windows(first_ping.len()).any(|window| window == first_ping));
+  assert!(written.ends_with(&[&second_ping[..], &close].concat()));
+}
+
+#[test]
+fn it_drops_the_connection_when_the_peer_does_not_answer_the_close_frame() {
+  let mut stream = UnresponsiveStream { inner: FakeStream::new(HANDSHAKE_MESSAGE.to_vec()), pongs_left: 0 };
+  let mut ws = WebSocket::new(&mut stream);
+  ws.accept().unwrap();
+  ws.close(CloseCode::Normal, "done").unwrap();
+
+  let mut handler = RecordingHandler::default();
+  ws.listen(&mut handler);
+
+  assert!(matches!(&handler.events[..], [Event::Close(close_frame)] if close_frame.code == CloseCode::Abnormal), "{:?}", handler.events);
+  assert!(stream.inner.written.ends_with(b"\x88\x06\x03\xE8done"));
+}
+
+#[test]
+fn it_passes_messages_to_the_handler() {
+  let text_frame = [vec![0b10000001, 0b10000101, 0, 0, 0, 0], b"Hello".to_vec()].concat();
+  let binary_frame = [0b10000010, 0b10000011, 0, 0, 0, 0, 1, 2, 3];
+  let mut fake_stream = FakeStream::new([HANDSHAKE_MESSAGE, &text_frame[..], &binary_frame[..]].concat());
+  let mut ws = WebSocket::new(&mut fake_stream);
+
+  let mut handler = RecordingHandler::default();
+  ws.open(&mut handler).unwrap();
+
+  assert!(matches!(&handler.events[0], Event::Open(path) if path == "/"));
+  assert!(matches!(&handler.events[1], Event::Message(Message::Text(text)) if text == "Hello"));
+  assert!(matches!(&handler.events[2], Event::Message(Message::Binary(bytes)) if *bytes == [1, 2, 3]));
+  assert!(matches!(&handler.events[3], Event::Close(close_frame) if close_frame.code == CloseCode::Abnormal));
+
+  // The handler echoed the text message
+  assert!(fake_stream.written.ends_with(&[&[0b10000001, 5][..], b"Hello"].concat()));
+}
+
+#[test]
+fn it_returns_an_error_for_a_malformed_handshake() {
+  let mut fake_stream = FakeStream::new(b"GET / HTTP/1.1\r\nHost example.com\r\n\r\n".to_vec());
+  let mut ws = WebSocket::new(&mut fake_stream);
+
+  let mut handler = RecordingHandler::default();
+  assert!(matches!(ws.open(&mut handler), Err(Error::Handshake(_))));
+  assert!(handler.events.is_empty());
+  assert!(matches!(ws.send_text("Hi"), Err(Error::ConnectionClosed)));
+  assert!(fake_stream.written.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+}
+
+#[test]
+fn it_answers_unsupported_versions_with_upgrade_required() {
+  let request = String::from_utf8(HANDSHAKE_MESSAGE.to_vec()).unwrap().replace("Version: 13", "Version: 8");
+  let mut fake_stream = FakeStream::new(request.into_bytes());
+  let mut ws = WebSocket::new(&mut fake_stream);
+
+  assert!(matches!(ws.accept(), Err(Error::Handshake(_))));
+
+  let response = String::from_utf8(fake_stream.written).unwrap();
+  assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+  assert!(response.contains("\r\nSec-WebSocket-Version: 13\r\n"));
+}
+
+#[test]
+fn it_answers_too_large_requests_with_an_error() {
+  let request = format!("GET / HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "a".repeat(4096));
+  let mut fake_stream = FakeStream::new(request.into_bytes());
+  let mut ws = WebSocket::new(&mut fake_stream);
+
+  assert!(matches!(ws.accept(), Err(Error::Capacity(_))));
+  assert!(fake_stream.written.starts_with(b"HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+}
+
+#[test]
+fn it_returns_an_error_when_the_peer_hangs_up_during_the_handshake() {
+  let mut fake_stream = FakeStream::new(b"GET / HTTP/1.1\r\n".to_vec());
+  let mut ws = WebSocket::new(&mut fake_stream);
+
+  assert!(matches!(ws.accept(), Err(Error::Handshake(_))));
+}
+
+#[test]
+fn it_negotiates_a_subprotocol() {
+  let request = String::from_utf8(HANDSHAKE_MESSAGE.to_vec()).unwrap().replace("\r\n\r\n", "\r\nSec-WebSocket-Protocol: telemetry, json-rpc\r\n\r\n");
+  let mut fake_stream = FakeStream::new(request.into_bytes());
+  let mut ws = WebSocket::new(&mut fake_stream);
+  ws.set_subprotocol_selector(SubprotocolSelector::preferred(&["json-rpc", "telemetry"]));
+
+  let request = ws.accept().unwrap();
+
+  assert_eq!(request.sec_websocket_protocols, vec!["telemetry", "json-rpc"]);
+  assert_eq!(ws.subprotocol(), Some("json-rpc"));
+  assert!(String::from_utf8(fake_stream.written).unwrap().contains("\r\nSec-WebSocket-Protocol: json-rpc\r\n"));
+}
+
+#[test]
+fn it_compresses_and_decompresses_with_permessage_deflate() {
+  // "Hello" compressed, as in the example from RFC 7692 section 7.2.3.1
+  let compressed_frame = [0b11000001, 0b10000111, 0, 0, 0, 0, 0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
+  let request = String::from_utf8(HANDSHAKE_MESSAGE.to_vec()).unwrap().replace("\r\n\r\n", "\r\nSec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\r\n");
+  let mut fake_stream = FakeStream::new([request.as_bytes(), &compressed_frame[..]].concat());
+  let mut ws = WebSocket::new(&mut fake_stream);
+  ws.set_deflate(DeflateConfig { server_no_context_takeover: true, ..DeflateConfig::default() });
+
+  let mut handler = RecordingHandler::default();
+  ws.open(&mut handler).unwrap();
+
+  assert!(matches!(&handler.events[1], Event::Message(Message::Text(text)) if text == "Hello"));
+
+  let written = String::from_utf8_lossy(&fake_stream.written).into_owned();
+  assert!(written.contains("\r\nSec-WebSocket-Extensions: permessage-deflate; server_no_context_takeover\r\n"));
+
+  // The handler's echo was compressed, with RSV1 set
+  assert!(fake_stream.written.ends_with(&[0b11000001, 7, 0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00]));
+}
+
+#[test]
+fn it_rejects_compressed_frames_when_deflate_was_not_negotiated() {
+  let compressed_frame = [0b11000001, 0b10000111, 0, 0, 0, 0, 0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
+  let mut fake_stream = FakeStream::new([HANDSHAKE_MESSAGE, &compressed_frame[..]].concat());
+  let mut ws = WebSocket::new(&mut fake_stream);
+  ws.set_deflate(DeflateConfig::default());
+
+  let mut handler = RecordingHandler::default();
+  ws.open(&mut handler).unwrap();
+
+  assert!(matches!(handler.events[1], Event::Error(Error::Protocol(CloseCode::ProtocolError))));
+}
+
+/// Appends a one-byte checksum to every message, and marks checksummed messages with RSV3.
+struct Checksum;
+
+impl Extension for Checksum {
+  fn name(&self) -> &str {
+    "x-checksum"
+  }
+
+  fn negotiate(&mut self, offer: &ExtensionOffer) -> Option<ExtensionParams> {
+    if offer.params.is_empty() { Some(Vec::new()) } else { None }
+  }
+
+  fn reserved_bits(&self) -> u8 {
+    0b00010000
+  }
+
+  fn decode_message(&mut self, mut message: DataFrame) -> Result<DataFrame, Error> {
+    let payload = message.payload_bytes.get_or_insert_with(Vec::new);
+    let checksum = payload.pop();
+    if !message.rsv3 || checksum != Some(payload.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))) {
+      return Err(Error::Protocol(CloseCode::InvalidPayload));
+    }
+    message.rsv3 = false;
+    Ok(message)
+  }
+
+  fn encode_message(&mut self, mut message: DataFrame) -> Result<DataFrame, Error> {
+    let payload = message.payload_bytes.get_or_insert_with(Vec::new);
+    payload.push(payload.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)));
+    message.rsv3 = true;
+    Ok(message)
+  }
+}
+
+#[test]
+fn it_runs_negotiated_extensions_on_every_message() {
+  let checksummed_frame = [0b10010001, 0b10000011, 0, 0, 0, 0, b'H', b'i', b'H'.wrapping_add(b'i')];
+  let bad_checksum_frame = [0b10010001, 0b10000011, 0, 0, 0, 0, b'H', b'i', 0];
+  let request = String::from_utf8(HANDSHAKE_MESSAGE.to_vec()).unwrap().replace("\r\n\r\n", "\r\nSec-WebSocket-Extensions: x-checksum\r\n\r\n");
+  let mut fake_stream = FakeStream::new([request.as_bytes(), &checksummed_frame[..], &bad_checksum_frame[..]].concat());
+  let mut ws = WebSocket::new(&mut fake_stream);
+  ws.add_extension(Box::new(Checksum));
+
+  let mut handler = RecordingHandler::default();
+  ws.open(&mut handler).unwrap();
+
+  assert!(matches!(&handler.events[1], Event::Message(Message::Text(text)) if text == "Hi"));
+  assert!(matches!(handler.events[2], Event::Error(Error::Protocol(CloseCode::InvalidPayload))));
+
+  let written = String::from_utf8_lossy(&fake_stream.written).into_owned();
+  assert!(written.contains("\r\nSec-WebSocket-Extensions: x-checksum\r\n"));
+
+  // The echo of "Hi" got a checksum and RSV3
+  let echo = [0b10010001, 3, b'H', b'i', b'H'.wrapping_add(b'i')];
+  assert!(fake_stream.written.windows(echo.len()).any(|window| window == echo));
+}
+
+struct TcpTestStream(TcpStream);
+
+impl WebSocketStream for TcpTestStream {
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+    self.0.read(buf)
+  }
+
+  fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+    self.0.write(buf)
+  }
+}
+
+// Reads a masked frame with a payload shorter than 126 bytes, and returns its opcode and unmasked payload.
+fn read_masked_frame(stream: &mut impl Read) -> (u8, Vec<u8>) {
+  let mut header = [0; 6];
+  stream.read_exact(&mut header).unwrap();
+  assert_eq!(header[1] & 0b10000000, 0b10000000, "clients must mask every frame");
+
+  let mut payload = vec![0; (header[1] & 0b01111111) as usize];
+  stream.read_exact(&mut payload).unwrap();
+  for (index, byte) in payload.iter_mut().enumerate() {
+    *byte ^= header[2 + index % 4];
+  }
+
+  (header[0] & 0b00001111, payload)
+}
+
+#[test]
+fn it_connects_to_a_server_and_masks_its_frames() {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let port = listener.local_addr().unwrap().port();
+
+  let server = thread::spawn(move || {
+    let mut stream = TcpTestStream(listener.accept().unwrap().0);
+    let request = {
+      let mut ws = WebSocket::new(&mut stream);
+      ws.set_subprotocol_selector(SubprotocolSelector::preferred(&["chat"]));
+      ws.accept().unwrap()
+    };
+    let mut stream = stream.0;
+
+    assert_eq!(read_masked_frame(&mut stream), (0x1, b"Hello".to_vec()));
+
+    // Servers never mask their frames
+    stream.write_all(&DataFrame::new(true, Opcode::Text, b"Hi".to_vec()).encode(None)).unwrap();
+    stream.write_all(&DataFrame::new(true, Opcode::Close, vec![0x03, 0xE8]).encode(None)).unwrap();
+
+    let echoes = (read_masked_frame(&mut stream), read_masked_frame(&mut stream));
+    (request, echoes)
+  });
+
+  let mut options = ConnectOptions { subprotocols: vec!["superchat".to_owned(), "chat".to_owned()], ..ConnectOptions::default() };
+  options.headers.insert("Authorization", "Bearer token");
+
+  let mut ws = WebSocket::connect_with_options(&format!("ws://127.0.0.1:{}/chat?room=1", port), &options).unwrap();
+  assert_eq!(ws.subprotocol(), Some("chat"));
+
+  ws.send_text("Hello").unwrap();
+  let mut handler = RecordingHandler::default();
+  ws.listen(&mut handler);
+
+  assert!(matches!(&handler.events[0], Event::Message(Message::Text(text)) if text == "Hi"));
+  assert!(matches!(&handler.events[1], Event::Close(CloseFrame { code: CloseCode::Normal, .. })));
+
+  let (request, echoes) = server.join().unwrap();
+  assert_eq!(request.path, "/chat?room=1");
+  assert_eq!(request.host, format!("127.0.0.1:{}", port));
+  assert_eq!(request.headers.get("Authorization"), Some("Bearer token".to_owned()));
+  assert_eq!(echoes, ((0x1, b"Hi".to_vec()), (0x8, vec![0x03, 0xE8])));
+}
+
+#[test]
+fn it_refuses_a_server_that_answers_with_the_wrong_accept_key() {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let port = listener.local_addr().unwrap().port();
+
+  let server = thread::spawn(move || {
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut request = [0; 1024];
+    let _ = stream.read(&mut request).unwrap();
+
+    // The accept key for the example key in RFC 6455, not the one the client sent
+    stream.write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n").unwrap();
+  });
+
+  assert!(matches!(WebSocket::connect(&format!("ws://127.0.0.1:{}/", port)), Err(Error::Handshake(_))));
+  server.join().unwrap();
+}
+
+#[test]
+fn it_refuses_urls_it_cannot_connect_to() {
+  assert!(matches!(WebSocket::connect("http://127.0.0.1/"), Err(Error::Handshake(_))));
+
+  #[cfg(not(feature = "rustls"))]
+  assert!(matches!(WebSocket::connect("wss://127.0.0.1/"), Err(Error::Handshake(_))));
+}
+
+// Closes the connection as soon as a message arrives.
+#[derive(Default)]
+struct ClosingHandler {
+  messages: Vec<Message>,
+}
+
+impl WebSocketHandler for ClosingHandler {
+  fn on_message(&mut self, socket: &mut WebSocket<'_>, message: Message) {
+    self.messages.push(message);
+    socket.close(CloseCode::Normal, "").unwrap();
+  }
+}
+
+fn echo(addr: SocketAddr, text: &str) -> Vec<Message> {
+  let mut ws = WebSocket::connect(&format!("ws://{}/", addr)).unwrap();
+  ws.send_text(text).unwrap();
+
+  let mut handler = ClosingHandler::default();
+  ws.listen(&mut handler);
+  handler.messages
+}
+
+#[test]
+fn it_serves_on_an_ephemeral_port() {
+  let server = WebSocketServer::bind("127.0.0.1:0", 2, RecordingHandler::default).unwrap();
+  let addr = server.local_addr().unwrap();
+  let handle = server.start().unwrap();
+
+  assert_ne!(addr.port(), 0);
+  assert_eq!(handle.local_addr(), addr);
+  assert_eq!(echo(addr, "Hello"), vec![Message::Text("Hello".to_owned())]);
+  handle.shutdown(Duration::from_secs(1));
+}
+
+#[test]
+fn it_serves_on_a_listener_that_is_already_bound() {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let bound_addr = listener.local_addr().unwrap();
+
+  let handle = WebSocketServer::from_listener(listener, 2, RecordingHandler::default).start().unwrap();
+
+  assert_eq!(handle.local_addr(), bound_addr);
+  assert_eq!(echo(bound_addr, "Hello"), vec![Message::Text("Hello".to_owned())]);
+  handle.shutdown(Duration::from_secs(1));
+}
+
+#[test]
+fn it_serves_on_ipv6() {
+  // IPv6 may be disabled on the machine running the tests
+  let server = match WebSocketServer::bind("[::1]:0", 2, RecordingHandler::default) {
+    Ok(server) => server,
+    Err(_) => return,
+  };
+  let handle = server.start().unwrap();
+
+  assert!(handle.local_addr().is_ipv6());
+  assert_eq!(echo(handle.local_addr(), "Hello"), vec![Message::Text("Hello".to_owned())]);
+  handle.shutdown(Duration::from_secs(1));
+}
+
+fn start_echo_server() -> ServerHandle {
+  WebSocketServer::bind("127.0.0.1:0", 4, RecordingHandler::default).unwrap().start().unwrap()
+}
+
+#[test]
+fn it_closes_connections_with_going_away_on_shutdown() {
+  let handle = start_echo_server();
+  let addr = handle.local_addr();
+
+  let (connected_sender, connected) = mpsc::channel();
+  let clients: Vec<_> = (0..2).map(|_| {
+    let connected_sender = connected_sender.clone();
+    thread::spawn(move || {
+      let mut ws = WebSocket::connect(&format!("ws://{}/", addr)).unwrap();
+      connected_sender.send(()).unwrap();
+
+      let mut handler = RecordingHandler::default();
+      ws.listen(&mut handler);
+      handler.events
+    })
+  }).collect();
+  for _ in 0..2 {
+    connected.recv().unwrap();
+  }
+
+  let started_at = Instant::now();
+  handle.shutdown(Duration::from_secs(5));
+  assert!(started_at.elapsed() < Duration::from_secs(5), "clients answered the close, so shutdown shouldn't wait for the deadline");
+
+  for client in clients {
+    let events = client.join().unwrap();
+    assert!(matches!(&events[..], [Event::Close(CloseFrame { code: CloseCode::GoingAway, .. })]), "{:?}", events);
+  }
+
+  // The listener is gone
+  assert!(WebSocket::connect(&format!("ws://{}/", addr)).is_err());
+}
+
+#[test]
+fn it_cuts_off_connections_that_do_not_close_within_the_deadline() {
+  let handle = start_echo_server();
+
+  // A client that completes the handshake but never answers the close frame
+  let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+  stream.write_all(HANDSHAKE_MESSAGE).unwrap();
+  let mut response = [0; 1024];
+  assert!(stream.read(&mut response).unwrap() > 0);
+
+  let started_at = Instant::now();
+  handle.shutdown(Duration::from_millis(300));
+  assert!(started_at.elapsed() >= Duration::from_millis(300));
+
+  let mut received = Vec::new();
+  stream.read_to_end(&mut received).unwrap();
+  assert_eq!(received[..4], [0b10001000, 29, 0x03, 0xE9]);
+  assert_eq!(&received[4..], b"the server is shutting down");
+}
+
+// Panics as soon as a client connects to "/panic"
+struct PanickingHandler;
+
+impl WebSocketHandler for PanickingHandler {
+  fn on_open(&mut self, _socket: &mut WebSocket<'_>, request: &HttpUpgradeRequest) {
+    if request.path == "/panic" {
+      panic!("the handler failed");
+    }
+  }
+}
+
+#[test]
+fn it_keeps_serving_after_a_handler_panics() {
+  // A single worker, so a panic that took it down would stop the server
+  let handle = WebSocketServer::bind("127.0.0.1:0", 1, || PanickingHandler).unwrap().start().unwrap();
+  let addr = handle.local_addr();
+
+  for _ in 0..3 {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(&upgrade_request("/panic", "")).unwrap();
+    // The connection is dropped, which may reset it
+    let _ = stream.read_to_end(&mut Vec::new());
+  }
+  wait_until(|| handle.connection_count() == 0);
+
+  let mut socket = WebSocket::connect(&format!("ws://{}/", addr)).unwrap();
+  socket.close(CloseCode::Normal, "still serving").unwrap();
+  handle.shutdown(Duration::from_secs(1));
+}
+
+// A client that completes the handshake and then keeps the connection open without doing anything
+fn open_idle_connection(addr: SocketAddr) -> TcpStream {
+  let mut stream = TcpStream::connect(addr).unwrap();
+  stream.write_all(HANDSHAKE_MESSAGE).unwrap();
+  let mut response = [0; 1024];
+  let size = stream.read(&mut response).unwrap();
+  assert!(response[..size].starts_with(b"HTTP/1.1 101"));
+  stream
+}
+
+fn send_handshake_and_read_response(addr: SocketAddr) -> Result<Vec<u8>, std::io::Error> {
+  let mut stream = TcpStream::connect(addr)?;
+  stream.write_all(HANDSHAKE_MESSAGE)?;
+  let mut response = Vec::new();
+  stream.read_to_end(&mut response)?;
+  Ok(response)
+}
+
+// Polls the condition until it holds, failing the test if it doesn't within a few seconds
+fn wait_until(mut condition: impl FnMut() -> bool) {
+  let deadline = Instant::now() + Duration::from_secs(5);
+  while !condition() {
+    assert!(Instant::now() < deadline, "timed out waiting for the condition");
+    thread::sleep(Duration::from_millis(10));
+  }
+}
+
+#[test]
+fn it_rejects_connections_beyond_the_limit_with_503() {
+  let mut server = WebSocketServer::bind("127.0.0.1:0", 2, RecordingHandler::default).unwrap();
+  server.set_max_connections(1, OverflowPolicy::Reject);
+  let handle = server.start().unwrap();
+  let addr = handle.local_addr();
+
+  let _idle = open_idle_connection(addr);
+
+  let response = send_handshake_and_read_response(addr).unwrap();
+  assert!(response.starts_with(b"HTTP/1.1 503 Service Unavailable\r\n"), "{}", String::from_utf8_lossy(&response));
+  assert!(matches!(WebSocket::connect(&format!("ws://{}/", addr)), Err(Error::Handshake(_))));
+
+  assert_eq!(handle.refused_connections(), 2);
+  assert_eq!(handle.connection_count(), 1);
+  handle.shutdown(Duration::from_secs(1));
+}
+
+#[test]
+fn it_keeps_accepting_while_refused_clients_are_slow() {
+  let mut server = WebSocketServer::bind("127.0.0.1:0", 2, RecordingHandler::default).unwrap();
+  server.set_max_connections(1, OverflowPolicy::Reject);
+  let handle = server.start().unwrap();
+  let addr = handle.local_addr();
+
+  let idle = open_idle_connection(addr);
+  let started = Instant::now();
+
+  // One refused client sends its request a byte at a time, the other sends nothing
+  let mut slow = TcpStream::connect(addr).unwrap();
+  let trickle = thread::spawn(move || {
+    for byte in HANDSHAKE_MESSAGE.iter().take(40) {
+      if slow.write_all(&[*byte]).is_err() {
+        break;
+      }
+      thread::sleep(Duration::from_millis(50));
+    }
+  });
+  let _silent = TcpStream::connect(addr).unwrap();
+  wait_until(|| handle.refused_connections() == 2);
+
+  drop(idle);
+  wait_until(|| handle.connection_count() == 0);
+  let mut socket = WebSocket::connect(&format!("ws://{}/", addr)).unwrap();
+  assert!(started.elapsed() < Duration::from_secs(1), "took {:?}", started.elapsed());
+
+  socket.close(CloseCode::Normal, "").unwrap();
+  trickle.join().unwrap();
+  handle.shutdown(Duration::from_secs(1));
+}
+
+#[test]
+fn it_closes_connections_beyond_the_limit() {
+  let mut server = WebSocketServer::bind("127.0.0.1:0", 2, RecordingHandler::default).unwrap();
+  server.set_max_connections(1, OverflowPolicy::Close);
+  let handle = server.start().unwrap();
+  let addr = handle.local_addr();
+
+  let _idle = open_idle_connection(addr);
+
+  // The connection is either closed or reset, depending on whether the server read the request
+  if let Ok(response) = send_handshake_and_read_response(addr) {
+    assert!(response.is_empty());
+  }
+
+  assert_eq!(handle.refused_connections(), 1);
+  handle.shutdown(Duration::from_secs(1));
+}
+
+#[test]
+fn it_queues_a_bounded_number_of_connections_beyond_the_limit() {
+  let mut server = WebSocketServer::bind("127.0.0.1:0", 1, RecordingHandler::default).unwrap();
+  server.set_max_connections(1, OverflowPolicy::Queue(1));
+  let handle = server.start().unwrap();
+  let addr = handle.local_addr();
+
+  let idle = open_idle_connection(addr);
+
+  // The second connection waits for the first one to close
+  let mut queued = TcpStream::connect(addr).unwrap();
+  queued.write_all(HANDSHAKE_MESSAGE).unwrap();
+  wait_until(|| handle.connection_count() == 2);
+
+  // The queue is full
+  let response = send_handshake_and_read_response(addr).unwrap();
+  assert!(response.starts_with(b"HTTP/1.1 503 Service Unavailable\r\n"));
+  assert_eq!(handle.refused_connections(), 1);
+
+  drop(idle);
+  let mut response = [0; 1024];
+  let size = queued.read(&mut response).unwrap();
+  assert!(response[..size].starts_with(b"HTTP/1.1 101"));
+
+  handle.shutdown(Duration::from_secs(1));
+}
+
+#[test]
+fn it_serves_queued_connections_only_once_a_served_one_closes() {
+  // Enough threads for every queued connection, so only the limit holds them back
+  let mut server = WebSocketServer::bind("127.0.0.1:0", 4, RecordingHandler::default).unwrap();
+  server.set_max_connections(1, OverflowPolicy::Queue(2));
+  let handle = server.start().unwrap();
+  let addr = handle.local_addr();
+
+  let idle = open_idle_connection(addr);
+
+  let mut queued = TcpStream::connect(addr).unwrap();
+  queued.write_all(HANDSHAKE_MESSAGE).unwrap();
+  wait_until(|| handle.connection_count() == 2);
+
+  queued.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+  let mut response = [0; 1024];
+  let error = queued.read(&mut response).unwrap_err();
+  assert!(matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut), "{:?}", error);
+
+  drop(idle);
+  queued.set_read_timeout(None).unwrap();
+  let size = queued.read(&mut response).unwrap();
+  assert!(response[..size].starts_with(b"HTTP/1.1 101"));
+
+  handle.shutdown(Duration::from_secs(1));
+}
+
+#[test]
+fn it_drops_connections_that_do_not_send_their_request_in_time() {
+  let mut server = WebSocketServer::bind("127.0.0.1:0", 2, RecordingHandler::default).unwrap();
+  server.set_max_connections(1, OverflowPolicy::Reject);
+  server.set_handshake_timeout(Duration::from_millis(200));
+  let handle = server.start().unwrap();
+  let addr = handle.local_addr();
+
+  // Takes the only slot without ever sending a request
+  let mut silent = TcpStream::connect(addr).unwrap();
+  wait_until(|| handle.connection_count() == 1);
+
+  let mut received = Vec::new();
+  silent.read_to_end(&mut received).unwrap();
+  assert!(received.is_empty());
+  wait_until(|| handle.connection_count() == 0);
+
+  let mut socket = WebSocket::connect(&format!("ws://{}/", addr)).unwrap();
+  socket.close(CloseCode::Normal, "").unwrap();
+  assert_eq!(handle.refused_connections(), 0);
+  handle.shutdown(Duration::from_secs(1));
+}
+
+// Relays every message to the other connections
+struct BroadcastHandler;
+
+impl WebSocketHandler for BroadcastHandler {
+  fn on_message(&mut self, socket: &mut WebSocket<'_>, message: Message) {
+    socket.registry().unwrap().broadcast_except(socket.connection_id().unwrap(), &message);
+  }
+}
+
+fn send_masked_text(stream: &mut TcpStream, text: &str) {
+  let mut encoder = FrameEncoder::new();
+  encoder.set_masking(Masking::Key([1, 2, 3, 4]));
+  stream.write_all(&encoder.encode_message(&Message::Text(text.to_owned()))).unwrap();
+}
+
+// Reads an unmasked text frame with a payload shorter than 126 bytes
+fn read_text_frame(stream: &mut TcpStream) -> String {
+  let mut header = [0; 2];
+  stream.read_exact(&mut header).unwrap();
+  assert_eq!(header[0], 0b10000001);
+
+  let mut payload = vec![0; header[1] as usize];
+  stream.read_exact(&mut payload).unwrap();
+  String::from_utf8(payload).unwrap()
+}
+
+#[test]
+fn it_sends_messages_to_other_connections_through_the_registry() {
+  let server = WebSocketServer::bind("127.0.0.1:0", 4, || BroadcastHandler).unwrap();
+  let registry = server.registry();
+  let handle = server.start().unwrap();
+
+  let mut clients: Vec<_> = (0..3).map(|_| open_idle_connection(handle.local_addr())).collect();
+  wait_until(|| registry.connections().len() == 3);
+
+  let connections = registry.connections();
+  assert!(connections.iter().all(|connection| connection.path == "/"));
+  assert_eq!(connections[0].remote_addr, clients[0].local_addr().unwrap());
+
+  send_masked_text(&mut clients[0], "Hello");
+  assert_eq!(read_text_frame(&mut clients[1]), "Hello");
+  assert_eq!(read_text_frame(&mut clients[2]), "Hello");
+
+  // The sender didn't get its own message, so this is the first thing it reads
+  registry.send_to(connections[0].id, &Message::Text("Just you".to_owned())).unwrap();
+  assert_eq!(read_text_frame(&mut clients[0]), "Just you");
+
+  assert_eq!(registry.broadcast(&Message::Text("Everyone".to_owned())), 3);
+  for client in clients.iter_mut() {
+    assert_eq!(read_text_frame(client), "Everyone");
+  }
+
+  drop(clients.remove(0));
+  wait_until(|| registry.connection(connections[0].id).is_none());
+  assert!(matches!(registry.send_to(connections[0].id, &Message::Text("Gone".to_owned())), Err(Error::ConnectionClosed)));
+
+  handle.shutdown(Duration::from_secs(1));
+}
+
+#[test]
+fn it_cuts_off_connections_that_fall_too_far_behind() {
+  let server = WebSocketServer::bind("127.0.0.1:0", 2, || BroadcastHandler).unwrap();
+  let registry = server.registry();
+  let handle = server.start().unwrap();
+
+  // The client never reads, so its messages pile up on the server
+  let _stalled = open_idle_connection(handle.local_addr());
+  wait_until(|| registry.connections().len() == 1);
+
+  let message = Message::Binary(vec![0; 16 * 1024]);
+  let mut broadcasts = 0;
+  while registry.broadcast(&message) == 1 {
+    broadcasts += 1;
+    assert!(broadcasts < 100_000, "the connection was never cut off");
+  }
+
+  wait_until(|| registry.connections().is_empty());
+  assert_eq!(handle.connection_count(), 0);
+  handle.shutdown(Duration::from_secs(1));
+}
+
+#[test]
+fn it_publishes_to_the_members_of_a_room() {
+  let server = WebSocketServer::bind("127.0.0.1:0", 4, RecordingHandler::default).unwrap();
+  let registry = server.registry();
+  let handle = server.start().unwrap();
+
+  let mut clients: Vec<_> = (0..3).map(|_| open_idle_connection(handle.local_addr())).collect();
+  wait_until(|| registry.connections().len() == 3);
+  let ids: Vec<_> = registry.connections().iter().map(|connection| connection.id).collect();
+
+  registry.join(ids[0], "news").unwrap();
+  registry.join(ids[1], "news").unwrap();
+  registry.join(ids[2], "sports").unwrap();
+  assert_eq!(registry.members("news"), vec![ids[0], ids[1]]);
+  assert_eq!(registry.rooms().into_iter().collect::<Vec<_>>(), vec![("news".to_owned(), 2), ("sports".to_owned(), 1)]);
+  assert_eq!(registry.connection(ids[0]).unwrap().rooms, vec!["news".to_owned()]);
+
+  assert_eq!(registry.publish("news", &Message::Text("Headline".to_owned())), 2);
+  assert_eq!(registry.publish("sports", &Message::Text("Score".to_owned())), 1);
+  assert_eq!(read_text_frame(&mut clients[0]), "Headline");
+  assert_eq!(read_text_frame(&mut clients[1]), "Headline");
+  assert_eq!(read_text_frame(&mut clients[2]), "Score");
+
+  assert!(registry.leave(ids[1], "news"));
+  assert!(!registry.leave(ids[1], "news"));
+  assert_eq!(registry.publish_except("news", ids[0], &Message::Text("Nobody".to_owned())), 0);
+  assert_eq!(registry.room_size("news"), 1);
+
+  // Closing a connection takes it out of its rooms
+  drop(clients.remove(0));
+  wait_until(|| registry.room_size("news") == 0);
+  assert_eq!(registry.rooms().into_iter().collect::<Vec<_>>(), vec![("sports".to_owned(), 1)]);
+  assert!(matches!(registry.join(ids[0], "news"), Err(Error::ConnectionClosed)));
+
+  handle.shutdown(Duration::from_secs(1));
+}
+
+// Tells the client what it was routed with
+struct RouteEchoHandler(&'static str);
+
+impl WebSocketHandler for RouteEchoHandler {
+  fn on_open(&mut self, socket: &mut WebSocket<'_>, request: &HttpUpgradeRequest) {
+    let params: Vec<String> = request.path_params.iter().chain(request.query.iter()).map(|(name, value)| format!("{}={}", name, value)).collect();
+    socket.send_text(&format!("{} {}", self.0, params.join(" "))).unwrap();
+  }
+}
+
+#[test]
+fn it_routes_upgrades_by_path() {
+  let mut router = Router::new();
+  router.add_route("/chat", || RouteEchoHandler("chat"));
+  router.add_route("/rooms/:room", || RouteEchoHandler("room"));
+  router.add_route("/metrics/*", || RouteEchoHandler("metrics"));
+  let handle = WebSocketServer::bind("127.0.0.1:0", 2, router).unwrap().start().unwrap();
+  let addr = handle.local_addr();
+
+  let first_message = |path: &str| {
+    let mut ws = WebSocket::connect(&format!("ws://{}{}", addr, path)).unwrap();
+    let mut handler = ClosingHandler::default();
+    ws.listen(&mut handler);
+    handler.messages
+  };
+  assert_eq!(first_message("/chat"), vec![Message::Text("chat ".to_owned())]);
+  assert_eq!(first_message("/rooms/news?name=Jo%20Lee"), vec![Message::Text("room room=news name=Jo Lee".to_owned())]);
+  assert_eq!(first_message("/metrics/cpu/1"), vec![Message::Text("metrics *=cpu/1".to_owned())]);
+
+  // HANDSHAKE_MESSAGE asks for "/", which has no route
+  let response = send_handshake_and_read_response(addr).unwrap();
+  assert!(response.starts_with(b"HTTP/1.1 404 Not Found\r\n"), "{}", String::from_utf8_lossy(&response));
+  assert!(matches!(WebSocket::connect(&format!("ws://{}/rooms", addr)), Err(Error::Handshake(_))));
+
+  handle.shutdown(Duration::from_secs(1));
+}
+
+// Tells the client which user the handshake interceptor attached
+struct UserHandler;
+
+impl WebSocketHandler for UserHandler {
+  fn on_open(&mut self, socket: &mut WebSocket<'_>, _request: &HttpUpgradeRequest) {
+    let user = socket.user_data::<String>().cloned().unwrap_or_default();
+    socket.send_text(&user).unwrap();
+  }
+}
+
+fn upgrade_request(path: &str, extra_headers: &str) -> Vec<u8> {
+  format!("GET {} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n{}\r\n", path, extra_headers).into_bytes()
+}
+
+// Reads the status line and headers of a response, leaving any frames after them in the stream
+fn read_response_head(stream: &mut TcpStream) -> String {
+  let mut response = Vec::new();
+  while !response.ends_with(b"\r\n\r\n") {
+    let mut byte = [0];
+    stream.read_exact(&mut byte).unwrap();
+    response.push(byte[0]);
+  }
+  String::from_utf8(response).unwrap()
+}
+
+#[test]
+fn it_lets_the_handshake_interceptor_accept_or_reject_upgrades() {
+  let mut server = WebSocketServer::bind("127.0.0.1:0", 2, || UserHandler).unwrap();
+  server.set_handshake_interceptor(|request| {
+    let token = request.query_param("token").map(str::to_owned).or_else(|| request.cookie("token"));
+    match token.as_deref() {
+      Some("secret") => HandshakeDecision::accept().with_header("X-User", "alice").with_user_data("alice".to_owned()),
+      _ => HandshakeDecision::reject(401, "invalid token").with_header("WWW-Authenticate", "Bearer"),
+    }
+  });
+  let handle = server.start().unwrap();
+
+  for (path, extra_headers) in [("/?token=secret", ""), ("/", "Cookie: theme=dark; token=secret\r\n")] {
+    let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+    stream.write_all(&upgrade_request(path, extra_headers)).unwrap();
+    let response = read_response_head(&mut stream);
+    assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", response);
+    assert!(response.contains("\r\nX-User: alice\r\n"), "{}", response);
+    assert_eq!(read_text_frame(&mut stream), "alice");
+  }
+
+  let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+  stream.write_all(&upgrade_request("/?token=wrong", "")).unwrap();
+  let mut response = String::new();
+  stream.read_to_string(&mut response).unwrap();
+  assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{}", response);
+  assert!(response.contains("\r\nWWW-Authenticate: Bearer\r\n"), "{}", response);
+  assert!(response.ends_with("\r\n\r\ninvalid token"), "{}", response);
+
+  handle.shutdown(Duration::from_secs(1));
+}
+
+#[test]
+fn it_answers_disallowed_origins_with_forbidden() {
+  let mut server = WebSocketServer::bind("127.0.0.1:0", 2, || UserHandler).unwrap();
+  server.set_origin_policy(OriginPolicy::subdomains(&["https://*.example.com"]));
+  let handle = server.start().unwrap();
+
+  for extra_headers in ["Origin: https://app.example.com\r\n", ""] {
+    let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+    stream.write_all(&upgrade_request("/", extra_headers)).unwrap();
+    let response = read_response_head(&mut stream);
+    assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", response);
+    // Wait for the handler's greeting, so it doesn't write to a closed connection
+    assert_eq!(read_text_frame(&mut stream), "");
+  }
+
+  let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+  stream.write_all(&upgrade_request("/", "Origin: https://evil.example\r\n")).unwrap();
+  let mut response = String::new();
+  stream.read_to_string(&mut response).unwrap();
+  assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{}", response);
+
+  handle.shutdown(Duration::from_secs(1));
+}